@@ -26,6 +26,34 @@ typedef struct {
     void* data;
 } RootsWorkClosure;
 
+// The plan that is used. See JuliaPlan in api.rs
+typedef enum {
+    MMTkPlanImmix = 0,
+    MMTkPlanStickyImmix = 1,
+    MMTkPlanMarkSweep = 2,
+    MMTkPlanNoGC = 3,
+} MMTkPlan;
+extern MMTkPlan mmtk_get_plan(void);
+extern bool mmtk_needs_write_barrier(void);
+
+// The allocation fastpath for an allocation semantics. See AllocFastpath in api.rs
+typedef enum {
+    MMTkAllocFastpathNone = 0,
+    MMTkAllocFastpathBumpPointer = 1,
+    MMTkAllocFastpathFreeList = 2,
+} MMTkAllocFastpathKind;
+typedef struct {
+    MMTkAllocFastpathKind kind;
+    size_t allocator_offset;
+    size_t cursor_offset;
+    size_t limit_offset;
+    size_t free_lists_offset;
+    size_t free_list_stride;
+    size_t num_size_classes;
+    size_t max_size;
+} MMTkAllocFastpath;
+extern MMTkAllocFastpath mmtk_get_alloc_fastpath(uint32_t semantics);
+
 /**
  * Allocation
  */
@@ -67,6 +95,40 @@ extern _Atomic(uintptr_t) JULIA_MALLOC_BYTES;
 extern void mmtk_gc_init(uintptr_t min_heap_size, uintptr_t max_heap_size, uintptr_t n_gcthreads, uintptr_t header_size, uintptr_t tag);
 extern bool mmtk_will_never_move(void* object);
 extern bool mmtk_process(char* name, char* value);
+
+// The statistics of a space. See HeapSpaceStats in space_stats.rs
+#define MMTK_SPACE_STATS_UNKNOWN SIZE_MAX
+typedef struct {
+    char name[32];
+    void* start;
+    size_t extent;
+    size_t reserved_pages;
+    size_t committed_pages;
+    size_t metadata_pages;
+    size_t used_pages;
+    size_t live_bytes;
+    size_t fragmentation_per_mille;
+    size_t objects;
+    size_t object_bytes;
+} MMTkSpaceStats;
+// count_objects walks the heap, and must only be set with the world stopped (no other thread runs Julia code or allocates).
+extern size_t mmtk_get_space_stats(MMTkSpaceStats* stats, size_t len, bool count_objects);
+extern void mmtk_print_space_stats(bool count_objects);
+
+// The result of setting or getting an option. See ConfigError in config.rs
+typedef enum {
+    MMTkConfigOk = 0,
+    MMTkConfigInvalidString = 1,
+    MMTkConfigUnknownOption = 2,
+    MMTkConfigInvalidValue = 3,
+    MMTkConfigAlreadyInitialized = 4,
+    MMTkConfigRejectedByCore = 5,
+    MMTkConfigBufferTooSmall = 6,
+} MMTkConfigError;
+extern MMTkConfigError mmtk_process_option(const char* name, const char* value);
+extern MMTkConfigError mmtk_get_option(const char* name, char* buf, size_t len);
+extern const char* mmtk_config_error_message(void);
+extern void mmtk_print_config(void);
 extern void mmtk_scan_region(void);
 extern void mmtk_handle_user_collection_request(void *tls, uint8_t collection);
 extern void mmtk_initialize_collection(void* tls);
@@ -77,12 +139,54 @@ extern void mmtk_register_finalizer(void* obj, void* function, bool is_ptr);
 extern void mmtk_run_finalizers_for_obj(void* obj);
 extern void mmtk_run_finalizers(bool at_exit);
 extern void mmtk_gc_poll(void *tls);
+extern bool mmtk_gc_idle_notification(void *tls, bool full_heap);
 extern void mmtk_julia_copy_stack_check(int copy_stack);
 extern void* mmtk_get_possibly_forwarded(void* object);
 extern void mmtk_block_thread_for_gc(void);
+// Call after mmtk_alloc, mmtk_alloc_large or mmtk_handle_user_collection_request returns, and throw an OutOfMemoryError if true. See oom.rs
+extern bool mmtk_take_pending_out_of_memory(void);
+extern void mmtk_safepoint_arrive(void);
+extern uint64_t mmtk_get_time_to_safepoint(int16_t tid);
+// Terminal: MMTk cannot be initialized again, and no thread may allocate after this. See collection.rs
+extern bool mmtk_gc_shutdown(void);
+
+// The time spent in each phase of a GC (in nanoseconds). See gc_stats.rs
+typedef struct {
+    uint64_t total;
+    uint64_t stop_the_world;
+    uint64_t mutator_roots;
+    uint64_t vm_specific_roots;
+    uint64_t closure;
+    uint64_t weak_refs;
+    uint64_t finalizers;
+    uint64_t sweep_vm_specific;
+    uint64_t release;
+} mmtk_gc_phase_times_t;
+extern void mmtk_get_last_gc_phase_times(mmtk_gc_phase_times_t* times);
+
+// Allocation counters. The arrays are indexed by the AllocationSemantics in mmtk-core. See alloc_stats.rs
+// The counters of a thread do not include its fastpath allocations since its last allocation in the binding.
+#define MMTK_NUM_ALLOCATION_SEMANTICS 7
+typedef struct {
+    uint64_t bytes[MMTK_NUM_ALLOCATION_SEMANTICS];
+    uint64_t objects[MMTK_NUM_ALLOCATION_SEMANTICS];
+} mmtk_alloc_counters_t;
+extern bool mmtk_get_thread_alloc_counters(int16_t tid, mmtk_alloc_counters_t* counters);
+extern void mmtk_get_total_alloc_counters(mmtk_alloc_counters_t* counters);
+extern uint64_t mmtk_get_mutator_allocated_bytes(MMTk_Mutator mutator);
+
+// The mutator iterator iterates over a snapshot of the mutators, and does not block binding or destroying mutators.
+typedef struct {
+    void* tls;
+    int16_t tid;
+    uint64_t bind_time;
+} mmtk_mutator_info_t;
 extern void* mmtk_new_mutator_iterator(void);
 extern void* mmtk_get_next_mutator_tls(void*);
+extern bool mmtk_get_next_mutator_info(void*, mmtk_mutator_info_t* info);
 extern void* mmtk_close_mutator_iterator(void*);
+typedef void (*mmtk_mutator_callback_t)(const mmtk_mutator_info_t* info, void* data);
+extern void mmtk_for_each_mutator(mmtk_mutator_callback_t callback, void* data);
 
 
 /**
@@ -91,8 +195,17 @@ extern void* mmtk_close_mutator_iterator(void*);
 extern size_t mmtk_free_bytes(void);
 extern size_t mmtk_total_bytes(void);
 extern size_t mmtk_used_bytes(void);
+extern size_t mmtk_adjust_external_memory(void *tls, intptr_t delta);
 extern void* mmtk_starting_heap_address(void);
 extern void* mmtk_last_heap_address(void);
+extern void mmtk_set_heap_soft_limit(size_t limit);
+extern size_t mmtk_get_heap_soft_limit(void);
+extern void mmtk_set_heap_hard_limit(size_t limit);
+extern size_t mmtk_get_heap_hard_limit(void);
+typedef void (*mmtk_memory_pressure_callback_t)(size_t heap_size, size_t max_heap_size, double threshold, bool thrashing);
+extern bool mmtk_register_memory_pressure_callback(mmtk_memory_pressure_callback_t callback, const double* thresholds, size_t n_thresholds);
+typedef void (*mmtk_finalization_callback_t)(void);
+extern void mmtk_register_finalization_callback(mmtk_finalization_callback_t callback);
 
 /**
  * Reference Processing
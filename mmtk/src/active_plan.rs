@@ -6,19 +6,19 @@ use mmtk::vm::ActivePlan;
 use mmtk::Mutator;
 use mmtk::{plan::ObjectQueue, scheduler::GCWorker, util::ObjectReference};
 
-use std::collections::HashMap;
+use crate::mutator_registry::{MutatorEntry, MutatorRegistry};
 use std::sync::RwLockReadGuard;
 
 pub struct JuliaMutatorIterator<'a> {
     // We do not use this field, but this lock guard makes sure that no concurrent access to MUTATORS.
-    _guard: RwLockReadGuard<'a, HashMap<Address, Address>>,
+    _guard: RwLockReadGuard<'a, MutatorRegistry>,
     vec: Vec<Address>,
     cursor: usize,
 }
 
 impl<'a> JuliaMutatorIterator<'a> {
-    fn new(guard: RwLockReadGuard<'a, HashMap<Address, Address>>) -> Self {
-        let vec = guard.keys().copied().collect();
+    fn new(guard: RwLockReadGuard<'a, MutatorRegistry>) -> Self {
+        let vec = guard.iter().map(|entry| entry.mutator).collect();
         Self {
             _guard: guard,
             vec,
@@ -44,16 +44,19 @@ pub struct VMActivePlan {}
 
 impl ActivePlan<JuliaVM> for VMActivePlan {
     fn number_of_mutators() -> usize {
-        Self::mutators().count()
+        MUTATORS.read().unwrap().len()
     }
 
     fn is_mutator(tls: VMThread) -> bool {
-        // FIXME have a tls field to check whether it is a mutator tls
-        MUTATORS.read().unwrap().keys().any(|mutator_addr| unsafe { &*mutator_addr.to_mut_ptr::<Mutator<JuliaVM>>() }.mutator_tls.0 == tls)
+        MUTATORS.read().unwrap().contains_tls(tls.0.to_address())
     }
 
-    fn mutator(_tls: VMMutatorThread) -> &'static mut Mutator<JuliaVM> {
-        unimplemented!()
+    fn mutator(tls: VMMutatorThread) -> &'static mut Mutator<JuliaVM> {
+        let mutators = MUTATORS.read().unwrap();
+        let entry = mutators
+            .get_by_tls(tls.0 .0.to_address())
+            .unwrap_or_else(|| panic!("{:?} is not a registered mutator", tls));
+        unsafe { entry.mutator() }
     }
 
     fn mutators<'a>() -> Box<dyn Iterator<Item = &'a mut Mutator<JuliaVM>> + 'a> {
@@ -71,26 +74,105 @@ impl ActivePlan<JuliaVM> for VMActivePlan {
     }
 }
 
-// Expose the mutator iterator so they can be used in C.
+// Expose the mutators to C. C gets a snapshot of the registered mutators, and we do not hold the lock of MUTATORS
+// while C iterates. So C can bind or destroy mutators during the iteration, and a mutator in the snapshot may be
+// destroyed by the time C sees it.
+
+/// The information of a mutator for C
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MutatorInfo {
+    /// The mutator TLS, i.e. the jl_tls_state_t of the thread
+    pub tls: OpaquePointer,
+    /// The Julia thread id
+    pub tid: i16,
+    /// When the mutator was bound (jl_hrtime)
+    pub bind_time: u64,
+}
+
+impl From<&MutatorEntry> for MutatorInfo {
+    fn from(entry: &MutatorEntry) -> Self {
+        Self {
+            tls: OpaquePointer::from_address(entry.tls),
+            tid: entry.tid,
+            bind_time: entry.bind_time,
+        }
+    }
+}
+
+/// Copy the information of the registered mutators.
+fn snapshot_mutators() -> Vec<MutatorInfo> {
+    MUTATORS
+        .read()
+        .unwrap()
+        .iter()
+        .map(MutatorInfo::from)
+        .collect()
+}
+
+/// An iterator over a snapshot of the mutators
+pub struct MutatorSnapshotIterator {
+    mutators: Vec<MutatorInfo>,
+    cursor: usize,
+}
+
+impl Iterator for MutatorSnapshotIterator {
+    type Item = MutatorInfo;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let info = self.mutators.get(self.cursor).copied();
+        self.cursor += 1;
+        info
+    }
+}
 
 #[no_mangle]
-pub extern "C" fn mmtk_new_mutator_iterator() -> *mut JuliaMutatorIterator<'static> {
-    let guard = MUTATORS.read().unwrap();
-    Box::into_raw(Box::new(JuliaMutatorIterator::new(guard)))
+pub extern "C" fn mmtk_new_mutator_iterator() -> *mut MutatorSnapshotIterator {
+    Box::into_raw(Box::new(MutatorSnapshotIterator {
+        mutators: snapshot_mutators(),
+        cursor: 0,
+    }))
 }
 
+/// Get the TLS of the next mutator, or NULL if there are no more mutators.
 #[no_mangle]
 pub unsafe extern "C" fn mmtk_get_next_mutator_tls(
-    iter: *mut JuliaMutatorIterator<'static>,
+    iter: *mut MutatorSnapshotIterator,
 ) -> OpaquePointer {
     match { iter.as_mut() }.unwrap().next() {
-        Some(m) => m.mutator_tls.0 .0,
+        Some(info) => info.tls,
         None => OpaquePointer::from_address(Address::ZERO),
     }
 }
 
+/// Get the information of the next mutator. Return false if there are no more mutators.
 #[no_mangle]
-pub unsafe extern "C" fn mmtk_close_mutator_iterator(iter: *mut JuliaMutatorIterator<'static>) {
+pub unsafe extern "C" fn mmtk_get_next_mutator_info(
+    iter: *mut MutatorSnapshotIterator,
+    info: *mut MutatorInfo,
+) -> bool {
+    match { iter.as_mut() }.unwrap().next() {
+        Some(next) => {
+            info.write(next);
+            true
+        }
+        None => false,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn mmtk_close_mutator_iterator(iter: *mut MutatorSnapshotIterator) {
     // The boxed pointer will get dropped
     let _to_drop = unsafe { Box::from_raw(iter) };
 }
+
+/// Call the callback for each mutator in a snapshot of the mutators, with the mutator information and `data`.
+#[no_mangle]
+pub extern "C" fn mmtk_for_each_mutator(
+    callback: extern "C" fn(info: *const MutatorInfo, data: *mut libc::c_void),
+    data: *mut libc::c_void,
+) {
+    for info in snapshot_mutators().iter() {
+        callback(info, data);
+    }
+}
@@ -0,0 +1,251 @@
+// CPU affinity and scheduling of the GC threads.
+//
+// The GC threads can be pinned to a set of CPUs with the `gc_thread_affinity` option (or MMTK_GC_THREAD_AFFINITY),
+// e.g. `0-7` or `0-3,8-11`, and the default number of GC threads is then the number of those CPUs. If it is not set,
+// the GC threads run on any CPU, and MMTk chooses the number of GC threads.
+//
+// With the `gc_thread_idle_priority` option (or MMTK_GC_THREAD_IDLE_PRIORITY), the GC threads run with SCHED_IDLE
+// between collections, and with SCHED_OTHER from the time a GC is requested. This only works on Linux. An
+// unprivileged thread can only switch from SCHED_IDLE back to SCHED_OTHER if RLIMIT_NICE allows its nice value,
+// so we reject the option if we could not undo it.
+
+use crate::config::{parse_bool, read_env_var};
+use log::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+/// The name of the option (for `mmtk_process`) for the CPUs that the GC threads run on.
+pub const GC_THREAD_AFFINITY_OPTION: &str = "gc_thread_affinity";
+/// The environment variable for the CPUs that the GC threads run on.
+pub const GC_THREAD_AFFINITY_ENV_VAR: &str = "MMTK_GC_THREAD_AFFINITY";
+/// The name of the option (for `mmtk_process`) to lower the priority of the GC threads between collections.
+pub const GC_THREAD_IDLE_PRIORITY_OPTION: &str = "gc_thread_idle_priority";
+/// The environment variable to lower the priority of the GC threads between collections.
+pub const GC_THREAD_IDLE_PRIORITY_ENV_VAR: &str = "MMTK_GC_THREAD_IDLE_PRIORITY";
+
+lazy_static! {
+    /// The CPUs set with the option. None if it is not set.
+    static ref GC_THREAD_AFFINITY: Mutex<Option<Vec<usize>>> = Mutex::new(
+        read_env_var(GC_THREAD_AFFINITY_ENV_VAR, parse_cpu_list)
+    );
+    static ref GC_THREAD_IDLE_PRIORITY: AtomicBool = AtomicBool::new(
+        read_env_var(GC_THREAD_IDLE_PRIORITY_ENV_VAR, parse_idle_priority).unwrap_or(false)
+    );
+    /// The kernel thread ids of the GC threads
+    static ref GC_THREAD_TIDS: Mutex<Vec<i32>> = Mutex::new(vec![]);
+}
+
+/// Set the `gc_thread_affinity` option. Return an error message if the CPU list is invalid. See config.rs
+pub fn set_gc_thread_affinity(value: &str) -> Result<(), String> {
+    parse_cpu_list(value).map(|cpus| *GC_THREAD_AFFINITY.lock().unwrap() = Some(cpus))
+}
+
+/// Set the `gc_thread_idle_priority` option. Return an error message if the value is invalid. See config.rs
+pub fn set_gc_thread_idle_priority(value: &str) -> Result<(), String> {
+    parse_idle_priority(value).map(|idle| GC_THREAD_IDLE_PRIORITY.store(idle, Ordering::Relaxed))
+}
+
+/// The CPUs set with the `gc_thread_affinity` option. None if it is not set.
+pub fn gc_thread_affinity() -> Option<Vec<usize>> {
+    GC_THREAD_AFFINITY.lock().unwrap().clone()
+}
+
+/// Do the GC threads run with SCHED_IDLE between collections?
+pub fn gc_thread_idle_priority() -> bool {
+    GC_THREAD_IDLE_PRIORITY.load(Ordering::Relaxed)
+}
+
+fn parse_idle_priority(value: &str) -> Result<bool, String> {
+    let idle = parse_bool(value).map_err(|_| {
+        format!(
+            "Invalid gc_thread_idle_priority: {} (expect true or false)",
+            value
+        )
+    })?;
+    if idle && !can_restore_normal_priority() {
+        return Err(format!(
+            "Invalid gc_thread_idle_priority: {} (the GC threads could not switch back from SCHED_IDLE: \
+             RLIMIT_NICE does not allow the current nice value, and the process does not have CAP_SYS_NICE)",
+            value
+        ));
+    }
+    Ok(idle)
+}
+
+/// Parse a CPU list such as `0-7` or `0,2,4-6`.
+fn parse_cpu_list(list: &str) -> Result<Vec<usize>, String> {
+    let invalid = || format!("Invalid CPU list: {} (expect e.g. 0-3,8)", list);
+    let mut cpus = vec![];
+    for range in list.split(',') {
+        let range = range.trim();
+        let (first, last) = match range.split_once('-') {
+            Some((first, last)) => (first, last),
+            None => (range, range),
+        };
+        let first = first.trim().parse::<usize>().map_err(|_| invalid())?;
+        let last = last.trim().parse::<usize>().map_err(|_| invalid())?;
+        if first > last {
+            return Err(invalid());
+        }
+        cpus.extend(first..=last);
+    }
+    cpus.sort_unstable();
+    cpus.dedup();
+    Ok(cpus)
+}
+
+/// Can a thread switch from SCHED_IDLE back to SCHED_OTHER? An unprivileged thread can do that if its nice value
+/// is within the range that RLIMIT_NICE allows (see sched(7)), i.e. 20 - nice <= the soft limit.
+#[cfg(target_os = "linux")]
+fn can_restore_normal_priority() -> bool {
+    if has_cap_sys_nice() {
+        return true;
+    }
+    unsafe {
+        let mut limit: libc::rlimit = std::mem::zeroed();
+        if libc::getrlimit(libc::RLIMIT_NICE, &mut limit) != 0 {
+            return false;
+        }
+        // getpriority can return -1 as a valid nice value. Clear errno to tell it from an error.
+        *libc::__errno_location() = 0;
+        let nice = libc::getpriority(libc::PRIO_PROCESS, 0);
+        if nice == -1 && *libc::__errno_location() != 0 {
+            return false;
+        }
+        limit.rlim_cur == libc::RLIM_INFINITY || (20 - nice as i64) <= limit.rlim_cur as i64
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn can_restore_normal_priority() -> bool {
+    // The option has no effect.
+    true
+}
+
+/// Does the process have CAP_SYS_NICE in its effective capabilities?
+#[cfg(target_os = "linux")]
+fn has_cap_sys_nice() -> bool {
+    const CAP_SYS_NICE: u32 = 23;
+    let status = match std::fs::read_to_string("/proc/self/status") {
+        Ok(status) => status,
+        Err(_) => return false,
+    };
+    status
+        .lines()
+        .find_map(|line| line.strip_prefix("CapEff:"))
+        .and_then(|caps| u64::from_str_radix(caps.trim(), 16).ok())
+        .is_some_and(|caps| caps & (1 << CAP_SYS_NICE) != 0)
+}
+
+/// Called on a GC thread when it starts: pin it to the CPUs, and lower its priority if needed.
+pub(crate) fn on_gc_thread_start() {
+    #[cfg(target_os = "linux")]
+    {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        if let Some(cpus) = GC_THREAD_AFFINITY.lock().unwrap().as_ref() {
+            set_affinity(tid, cpus);
+        }
+        GC_THREAD_TIDS.lock().unwrap().push(tid);
+        if GC_THREAD_IDLE_PRIORITY.load(Ordering::Relaxed) {
+            set_idle_priority(tid, true);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    if GC_THREAD_AFFINITY.lock().unwrap().is_some()
+        || GC_THREAD_IDLE_PRIORITY.load(Ordering::Relaxed)
+    {
+        warn!("GC thread affinity and priority are only supported on Linux");
+    }
+}
+
+/// Called on a GC thread when it exits.
+pub(crate) fn on_gc_thread_exit() {
+    #[cfg(target_os = "linux")]
+    {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as i32;
+        GC_THREAD_TIDS.lock().unwrap().retain(|t| *t != tid);
+    }
+}
+
+/// A GC is requested. Restore the normal priority of the GC threads before they start the GC.
+pub(crate) fn on_gc_start() {
+    if GC_THREAD_IDLE_PRIORITY.load(Ordering::Relaxed) {
+        for tid in GC_THREAD_TIDS.lock().unwrap().iter() {
+            set_idle_priority(*tid, false);
+        }
+    }
+}
+
+/// A GC ends. Lower the priority of the GC threads until the next GC.
+pub(crate) fn on_gc_end() {
+    if GC_THREAD_IDLE_PRIORITY.load(Ordering::Relaxed) {
+        for tid in GC_THREAD_TIDS.lock().unwrap().iter() {
+            set_idle_priority(*tid, true);
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn set_affinity(tid: i32, cpus: &[usize]) {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for cpu in cpus.iter().filter(|cpu| **cpu < libc::CPU_SETSIZE as usize) {
+            libc::CPU_SET(*cpu, &mut set);
+        }
+        if libc::sched_setaffinity(tid, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            warn!(
+                "Failed to set the CPU affinity of GC thread {} to {:?}: {}",
+                tid,
+                cpus,
+                std::io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+/// Switch the GC thread between SCHED_IDLE and SCHED_OTHER.
+#[cfg(target_os = "linux")]
+fn set_idle_priority(tid: i32, idle: bool) {
+    let policy = if idle {
+        libc::SCHED_IDLE
+    } else {
+        libc::SCHED_OTHER
+    };
+    let param = libc::sched_param { sched_priority: 0 };
+    if unsafe { libc::sched_setscheduler(tid, policy, &param) } != 0 {
+        warn!(
+            "Failed to set the scheduling policy of GC thread {} (idle = {}): {}",
+            tid,
+            idle,
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_idle_priority(_tid: i32, _idle: bool) {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_cpu_list_values() {
+        assert_eq!(parse_cpu_list("3"), Ok(vec![3]));
+        assert_eq!(parse_cpu_list("0-3"), Ok(vec![0, 1, 2, 3]));
+        assert_eq!(parse_cpu_list("0-1,8-9"), Ok(vec![0, 1, 8, 9]));
+        assert_eq!(parse_cpu_list(" 4 , 0 - 1 "), Ok(vec![0, 1, 4]));
+        // Sorted, without duplicates
+        assert_eq!(parse_cpu_list("6,2-4,3"), Ok(vec![2, 3, 4, 6]));
+    }
+
+    #[test]
+    fn parse_cpu_list_rejects_invalid_lists() {
+        assert!(parse_cpu_list("").is_err());
+        assert!(parse_cpu_list("0-").is_err());
+        assert!(parse_cpu_list("3-1").is_err());
+        assert!(parse_cpu_list("0,,1").is_err());
+        assert!(parse_cpu_list("-1").is_err());
+        assert!(parse_cpu_list("a-b").is_err());
+    }
+}
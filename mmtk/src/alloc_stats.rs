@@ -0,0 +1,342 @@
+// Per-mutator allocation counters, for @allocated, Base.gc_num().allocd and per-thread allocation accounting.
+//
+// Most allocations are done in the allocation fastpath in Julia (bump pointer allocation for the Default and
+// the Immortal semantics), which does not call into the binding. So we count the bytes in two ways:
+//   - an allocation that goes through mmtk_alloc/mmtk_alloc_large is counted with its size and as one object,
+//   - the bytes allocated by the fastpath are counted from the delta of the bump cursor: we remember the cursor
+//     after each allocation in the binding (the slowpath of the fastpath), and add the distance the cursor moved
+//     since then at the next allocation in the binding with the same semantics, and when a GC starts. The GC may
+//     reset the allocators, so we remember the cursors again when it ends.
+// The bytes from the fastpath include alignment padding, and the objects allocated by the fastpath are not counted
+// in the object counts.
+//
+// The counters of destroyed mutators are added to the retired counters, so the totals do not go backwards.
+
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+use crate::mutator_registry::MutatorEntry;
+use crate::{JuliaVM, MUTATORS, SINGLETON};
+use mmtk::util::alloc::{AllocatorSelector, BumpAllocator, ImmixAllocator};
+use mmtk::util::Address;
+use mmtk::{memory_manager, AllocationSemantics, Mutator};
+use std::cell::Cell;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// The number of AllocationSemantics
+pub const NUM_SEMANTICS: usize = 7;
+
+// Check NUM_SEMANTICS against AllocationSemantics. The match does not compile if mmtk-core adds a semantics.
+const _: () = {
+    const fn index(semantics: AllocationSemantics) -> usize {
+        match semantics {
+            AllocationSemantics::Default
+            | AllocationSemantics::Immortal
+            | AllocationSemantics::Los
+            | AllocationSemantics::Code
+            | AllocationSemantics::ReadOnly
+            | AllocationSemantics::LargeCode
+            | AllocationSemantics::NonMoving => semantics as usize,
+        }
+    }
+    assert!(index(AllocationSemantics::NonMoving) + 1 == NUM_SEMANTICS);
+};
+
+/// The semantics that Julia allocates with a bump pointer fastpath
+const FASTPATH_SEMANTICS: [AllocationSemantics; 2] =
+    [AllocationSemantics::Default, AllocationSemantics::Immortal];
+
+/// The allocation counters of a mutator
+#[derive(Default)]
+pub struct AllocCounters {
+    bytes: [AtomicU64; NUM_SEMANTICS],
+    objects: [AtomicU64; NUM_SEMANTICS],
+    /// The bump cursor after the last allocation in the binding, for each semantics in FASTPATH_SEMANTICS.
+    /// Zero if the allocator has no buffer.
+    last_cursor: [AtomicUsize; FASTPATH_SEMANTICS.len()],
+}
+
+impl AllocCounters {
+    fn add(&self, semantics: AllocationSemantics, bytes: usize, objects: usize) {
+        let i = semantics as usize;
+        self.bytes[i].fetch_add(bytes as u64, Ordering::Relaxed);
+        self.objects[i].fetch_add(objects as u64, Ordering::Relaxed);
+    }
+
+    fn add_all(&self, counters: &AllocCountersSnapshot) {
+        for i in 0..NUM_SEMANTICS {
+            self.bytes[i].fetch_add(counters.bytes[i], Ordering::Relaxed);
+            self.objects[i].fetch_add(counters.objects[i], Ordering::Relaxed);
+        }
+    }
+
+    fn snapshot(&self) -> AllocCountersSnapshot {
+        let mut snapshot = AllocCountersSnapshot::default();
+        for i in 0..NUM_SEMANTICS {
+            snapshot.bytes[i] = self.bytes[i].load(Ordering::Relaxed);
+            snapshot.objects[i] = self.objects[i].load(Ordering::Relaxed);
+        }
+        snapshot
+    }
+
+    /// Count the bytes allocated by the fastpath for FASTPATH_SEMANTICS[i] since we last saw its bump cursor, and
+    /// remember the current cursor.
+    fn sync_cursor(&self, i: usize, cursor: Address) {
+        let last = self.last_cursor[i].swap(cursor.as_usize(), Ordering::Relaxed);
+        // The cursor is zero if the allocator has no buffer, e.g. it was reset by a GC.
+        if last != 0 && cursor.as_usize() >= last {
+            self.add(FASTPATH_SEMANTICS[i], cursor.as_usize() - last, 0);
+        }
+    }
+
+    /// Remember the bump cursor of FASTPATH_SEMANTICS[i] without counting the bytes since the last cursor.
+    fn remember_cursor(&self, i: usize, cursor: Address) {
+        self.last_cursor[i].store(cursor.as_usize(), Ordering::Relaxed);
+    }
+
+    /// Count the bytes allocated by the fastpath of the mutator for all the semantics.
+    fn sync_fastpath(&self, mutator: &Mutator<JuliaVM>) {
+        for (i, semantics) in FASTPATH_SEMANTICS.iter().enumerate() {
+            if let Some(cursor) = bump_cursor(mutator, *semantics) {
+                self.sync_cursor(i, cursor);
+            }
+        }
+    }
+
+    /// Remember the bump cursors of the mutator for all the semantics.
+    fn remember_cursors(&self, mutator: &Mutator<JuliaVM>) {
+        for (i, semantics) in FASTPATH_SEMANTICS.iter().enumerate() {
+            if let Some(cursor) = bump_cursor(mutator, *semantics) {
+                self.remember_cursor(i, cursor);
+            }
+        }
+    }
+}
+
+/// The allocation counters exposed to C. The arrays are indexed by AllocationSemantics.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllocCountersSnapshot {
+    pub bytes: [u64; NUM_SEMANTICS],
+    pub objects: [u64; NUM_SEMANTICS],
+}
+
+impl AllocCountersSnapshot {
+    fn total_bytes(&self) -> u64 {
+        self.bytes.iter().sum()
+    }
+}
+
+lazy_static! {
+    /// The counters of the mutators that are destroyed
+    static ref RETIRED: AllocCounters = AllocCounters::default();
+}
+
+thread_local! {
+    /// The mutator (in jl_tls_state_t) of this thread and its counters. The counters are owned by the entry of the
+    /// mutator in MUTATORS, and we clear this when the mutator is destroyed.
+    static CURRENT_COUNTERS: Cell<(Address, *const AllocCounters)> =
+        const { Cell::new((Address::ZERO, std::ptr::null())) };
+}
+
+/// The bump cursor of the allocator for the semantics, if the allocator is a bump pointer allocator.
+fn bump_cursor(mutator: &Mutator<JuliaVM>, semantics: AllocationSemantics) -> Option<Address> {
+    let selector = memory_manager::get_allocator_mapping(&SINGLETON, semantics);
+    match selector {
+        AllocatorSelector::Immix(_) => Some(
+            unsafe { mutator.allocator_impl::<ImmixAllocator<JuliaVM>>(selector) }
+                .bump_pointer
+                .cursor,
+        ),
+        AllocatorSelector::BumpPointer(_) => Some(
+            unsafe { mutator.allocator_impl::<BumpAllocator<JuliaVM>>(selector) }
+                .bump_pointer
+                .cursor,
+        ),
+        _ => None,
+    }
+}
+
+/// The index of the semantics in FASTPATH_SEMANTICS and the bump cursor of its allocator. None if Julia does not
+/// allocate with the semantics in the fastpath.
+fn fastpath_cursor(
+    mutator: &Mutator<JuliaVM>,
+    semantics: AllocationSemantics,
+) -> Option<(usize, Address)> {
+    let i = FASTPATH_SEMANTICS
+        .iter()
+        .position(|fastpath| *fastpath as usize == semantics as usize)?;
+    Some((i, bump_cursor(mutator, semantics)?))
+}
+
+/// The mutator is bound on the current thread. Remember its counters for the allocations on this thread.
+pub(crate) fn set_current_mutator(entry: &MutatorEntry) {
+    CURRENT_COUNTERS
+        .with(|current| current.set((entry.mutator, Arc::as_ptr(&entry.alloc_counters))));
+}
+
+/// Call `f` with the counters of the mutator (None if the mutator is not registered). Usually, this is the mutator of
+/// the current thread, and we do not need to look it up.
+fn with_counters<R>(mutator: Address, f: impl FnOnce(Option<&AllocCounters>) -> R) -> R {
+    let (current, counters) = CURRENT_COUNTERS.with(|current| current.get());
+    if current == mutator {
+        // The entry of the mutator is not removed while the mutator is in use.
+        return f(Some(unsafe { &*counters }));
+    }
+    // Do not hold the lock in `f`: the allocation may block for a GC.
+    let counters = MUTATORS
+        .read()
+        .unwrap()
+        .get(mutator)
+        .map(|entry| entry.alloc_counters.clone());
+    f(counters.as_deref())
+}
+
+/// Allocate in the binding, and count the allocation.
+pub(crate) fn alloc_and_count(
+    mutator: &mut Mutator<JuliaVM>,
+    size: usize,
+    align: usize,
+    offset: usize,
+    semantics: AllocationSemantics,
+) -> Address {
+    with_counters(Address::from_mut_ptr(mutator), |counters| {
+        let counters = match counters {
+            Some(counters) => counters,
+            None => {
+                return memory_manager::alloc::<JuliaVM>(mutator, size, align, offset, semantics)
+            }
+        };
+        // Only the allocator of the semantics may move to a new buffer. Count its fastpath allocations before that.
+        if let Some((i, cursor)) = fastpath_cursor(mutator, semantics) {
+            counters.sync_cursor(i, cursor);
+        }
+        let result = memory_manager::alloc::<JuliaVM>(mutator, size, align, offset, semantics);
+        counters.add(semantics, size, 1);
+        // Do not count this allocation again as a fastpath allocation.
+        if let Some((i, cursor)) = fastpath_cursor(mutator, semantics) {
+            counters.remember_cursor(i, cursor);
+        }
+        result
+    })
+}
+
+/// A GC starts. Count the fastpath allocations of all the mutators before the GC resets the allocators.
+pub(crate) fn on_gc_start() {
+    for entry in MUTATORS.read().unwrap().iter() {
+        entry
+            .alloc_counters
+            .sync_fastpath(unsafe { entry.mutator() });
+    }
+}
+
+/// A GC ends, and the mutators are not resumed yet. Remember the bump cursors, which the GC may have reset.
+pub(crate) fn on_gc_end() {
+    for entry in MUTATORS.read().unwrap().iter() {
+        entry
+            .alloc_counters
+            .remember_cursors(unsafe { entry.mutator() });
+    }
+}
+
+/// The mutator is destroyed. Add its counters to the retired counters.
+pub(crate) fn retire(entry: &MutatorEntry) {
+    entry
+        .alloc_counters
+        .sync_fastpath(unsafe { entry.mutator() });
+    RETIRED.add_all(&entry.alloc_counters.snapshot());
+    CURRENT_COUNTERS.with(|current| {
+        if current.get().0 == entry.mutator {
+            current.set((Address::ZERO, std::ptr::null()));
+        }
+    });
+}
+
+/// Get the allocation counters of the thread. Return false if the thread does not have a mutator.
+/// The counters do not include the bytes that the thread allocated in the fastpath since its last allocation in the
+/// binding (or the last GC): we cannot read the bump cursors of another thread while it allocates. Use
+/// `mmtk_get_mutator_allocated_bytes` on the thread itself for an up-to-date count.
+#[no_mangle]
+pub extern "C" fn mmtk_get_thread_alloc_counters(
+    tid: i16,
+    counters: *mut AllocCountersSnapshot,
+) -> bool {
+    let snapshot = match MUTATORS.read().unwrap().get_by_tid(tid) {
+        Some(entry) => entry.alloc_counters.snapshot(),
+        None => return false,
+    };
+    unsafe { counters.write(snapshot) };
+    true
+}
+
+/// Get the sum of the allocation counters of all the mutators, including the destroyed ones.
+/// Like `mmtk_get_thread_alloc_counters`, this does not include the recent fastpath allocations of each mutator.
+#[no_mangle]
+pub extern "C" fn mmtk_get_total_alloc_counters(counters: *mut AllocCountersSnapshot) {
+    let total = AllocCounters::default();
+    total.add_all(&RETIRED.snapshot());
+    for entry in MUTATORS.read().unwrap().iter() {
+        total.add_all(&entry.alloc_counters.snapshot());
+    }
+    unsafe { counters.write(total.snapshot()) };
+}
+
+/// Get the bytes allocated by the mutator, including the fastpath allocations up to now. This should be called
+/// on the thread of the mutator, e.g. for @allocated.
+#[no_mangle]
+pub extern "C" fn mmtk_get_mutator_allocated_bytes(mutator: *mut Mutator<JuliaVM>) -> u64 {
+    let mutator = unsafe { &mut *mutator };
+    with_counters(Address::from_mut_ptr(mutator), |counters| match counters {
+        Some(counters) => {
+            counters.sync_fastpath(mutator);
+            counters.snapshot().total_bytes()
+        }
+        None => 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEFAULT: usize = 0;
+
+    fn cursor(addr: usize) -> Address {
+        unsafe { Address::from_usize(addr) }
+    }
+
+    /// An allocation in the binding: sync the cursor before, and remember it after the allocation.
+    fn alloc_in_binding(counters: &AllocCounters, before: usize, size: usize, after: usize) {
+        counters.sync_cursor(DEFAULT, cursor(before));
+        counters.add(AllocationSemantics::Default, size, 1);
+        counters.remember_cursor(DEFAULT, cursor(after));
+    }
+
+    #[test]
+    fn count_fastpath_across_gc_reset() {
+        let counters = AllocCounters::default();
+        // The first allocation gets a buffer, and the fastpath allocates 256 bytes in it.
+        alloc_in_binding(&counters, 0, 64, 0x10040);
+        // The GC starts, and resets the allocator.
+        counters.sync_cursor(DEFAULT, cursor(0x10140));
+        counters.remember_cursor(DEFAULT, cursor(0));
+        // The next allocation gets a new buffer, and the fastpath allocates 128 bytes in it.
+        alloc_in_binding(&counters, 0, 32, 0x20020);
+        counters.sync_cursor(DEFAULT, cursor(0x200a0));
+
+        let snapshot = counters.snapshot();
+        assert_eq!(snapshot.bytes[AllocationSemantics::Default as usize], 480);
+        assert_eq!(snapshot.objects[AllocationSemantics::Default as usize], 2);
+    }
+
+    #[test]
+    fn count_fastpath_across_gc_without_reset() {
+        let counters = AllocCounters::default();
+        alloc_in_binding(&counters, 0, 64, 0x10040);
+        // The GC keeps the buffer of the allocator, and the fastpath allocates 64 bytes after the GC.
+        counters.sync_cursor(DEFAULT, cursor(0x10080));
+        counters.remember_cursor(DEFAULT, cursor(0x10080));
+        counters.sync_cursor(DEFAULT, cursor(0x100c0));
+
+        assert_eq!(counters.snapshot().total_bytes(), 64 + 64 + 64);
+    }
+}
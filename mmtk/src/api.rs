@@ -1,5 +1,6 @@
 // All functions here are extern function. There is no point for marking them as unsafe.
 #![allow(clippy::not_unsafe_ptr_arg_deref)]
+use crate::mutator_registry::MutatorEntry;
 use crate::JuliaVM;
 use crate::JULIA_HEADER_SIZE;
 use crate::MMTK_SIDE_LOG_BIT_BASE_ADDRESS;
@@ -15,10 +16,191 @@ use mmtk::util::opaque_pointer::*;
 use mmtk::util::{Address, ObjectReference, OpaquePointer};
 use mmtk::AllocationSemantics;
 use mmtk::Mutator;
-use std::ffi::CStr;
 use std::sync::atomic::AtomicIsize;
 use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 
+/// The plans that the binding supports. The plan is selected with a cargo feature at build time,
+/// or with the `plan` option or MMTK_PLAN at runtime.
+const SUPPORTED_PLANS: [mmtk::util::options::PlanSelector; 4] = [
+    mmtk::util::options::PlanSelector::Immix,
+    mmtk::util::options::PlanSelector::StickyImmix,
+    mmtk::util::options::PlanSelector::MarkSweep,
+    mmtk::util::options::PlanSelector::NoGC,
+];
+
+fn is_supported_plan(plan: mmtk::util::options::PlanSelector) -> bool {
+    SUPPORTED_PLANS.contains(&plan)
+}
+
+/// The plan that is used, for the runtime to pick the matching allocation fastpath and write barrier.
+/// This maps to MMTkPlan in mmtk.h.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JuliaPlan {
+    Immix = 0,
+    StickyImmix = 1,
+    MarkSweep = 2,
+    NoGC = 3,
+}
+
+/// Get the plan that is used. This can only be called after mmtk_gc_init.
+#[no_mangle]
+pub extern "C" fn mmtk_get_plan() -> JuliaPlan {
+    use mmtk::util::options::PlanSelector;
+    match *SINGLETON.get_options().plan {
+        PlanSelector::Immix => JuliaPlan::Immix,
+        PlanSelector::StickyImmix => JuliaPlan::StickyImmix,
+        PlanSelector::MarkSweep => JuliaPlan::MarkSweep,
+        PlanSelector::NoGC => JuliaPlan::NoGC,
+        plan => unreachable!("Unsupported plan {:?}", plan),
+    }
+}
+
+/// Does the plan need the write barrier (i.e. is it a generational plan)?
+#[no_mangle]
+pub extern "C" fn mmtk_needs_write_barrier() -> bool {
+    PLAN_NEEDS_LOG_BIT.load(Ordering::Relaxed)
+}
+
+/// The kind of the allocation fastpath for an allocation semantics. This maps to MMTkAllocFastpathKind in mmtk.h.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocFastpathKind {
+    /// There is no fastpath. Call mmtk_alloc.
+    None = 0,
+    /// Bump the cursor in the allocator, and call mmtk_alloc if the cursor would pass the limit.
+    BumpPointer = 1,
+    /// Allocate from the free list of the size class in the allocator. Julia does not inline the free list
+    /// allocation yet, and calls mmtk_alloc for objects up to `max_size`.
+    FreeList = 2,
+}
+
+/// The allocation fastpath for an allocation semantics, for the runtime and the compiler to inline the allocation.
+/// The offsets are from the start of the mutator in jl_tls_state_t. This maps to MMTkAllocFastpath in mmtk.h.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct AllocFastpath {
+    pub kind: AllocFastpathKind,
+    /// The offset of the allocator
+    pub allocator_offset: usize,
+    /// The offset of the bump cursor. Only for BumpPointer.
+    pub cursor_offset: usize,
+    /// The offset of the bump limit. Only for BumpPointer.
+    pub limit_offset: usize,
+    /// The offset of the block lists of the size classes (one BlockList for each size class, whose first block
+    /// has free cells). Only for FreeList.
+    pub free_lists_offset: usize,
+    /// The size of the BlockList of a size class (in bytes). Only for FreeList.
+    pub free_list_stride: usize,
+    /// The number of size classes. Only for FreeList.
+    pub num_size_classes: usize,
+    /// The max object size (in bytes) that can be allocated with the allocator.
+    pub max_size: usize,
+}
+
+impl AllocFastpath {
+    /// No fastpath, for a semantics that does not exist
+    const NONE: Self = AllocFastpath {
+        kind: AllocFastpathKind::None,
+        allocator_offset: 0,
+        cursor_offset: 0,
+        limit_offset: 0,
+        free_lists_offset: 0,
+        free_list_stride: 0,
+        num_size_classes: 0,
+        max_size: 0,
+    };
+}
+
+/// The AllocationSemantics from its value in C. None if the value is not a semantics.
+fn allocation_semantics(semantics: u32) -> Option<AllocationSemantics> {
+    match semantics {
+        0 => Some(AllocationSemantics::Default),
+        1 => Some(AllocationSemantics::Immortal),
+        2 => Some(AllocationSemantics::Los),
+        3 => Some(AllocationSemantics::Code),
+        4 => Some(AllocationSemantics::ReadOnly),
+        5 => Some(AllocationSemantics::LargeCode),
+        6 => Some(AllocationSemantics::NonMoving),
+        _ => None,
+    }
+}
+
+/// Get the allocation fastpath for the semantics (the value of AllocationSemantics) with the plan that is used.
+/// The kind is None if the semantics does not exist. This can only be called after mmtk_gc_init.
+#[no_mangle]
+pub extern "C" fn mmtk_get_alloc_fastpath(semantics: u32) -> AllocFastpath {
+    use memoffset::offset_of;
+    use mmtk::policy::marksweepspace::native_ms::{BlockList, BlockLists};
+    use mmtk::util::alloc::{
+        AllocatorSelector, BumpAllocator, BumpPointer, FreeListAllocator, ImmixAllocator,
+    };
+
+    let semantics = match allocation_semantics(semantics) {
+        Some(semantics) => semantics,
+        None => {
+            warn!(
+                "mmtk_get_alloc_fastpath: {} is not an allocation semantics",
+                semantics
+            );
+            return AllocFastpath::NONE;
+        }
+    };
+    let selector = memory_manager::get_allocator_mapping(&SINGLETON, semantics);
+    let allocator_offset = memory_manager::get_allocator_base_offset(selector);
+    let max_size = match selector {
+        // The bump pointer allocator (for the immortal space) and the large object allocator take any size.
+        AllocatorSelector::BumpPointer(_) | AllocatorSelector::LargeObject(_) => usize::MAX,
+        AllocatorSelector::Immix(_) => mmtk::policy::immix::MAX_IMMIX_OBJECT_SIZE,
+        _ => {
+            SINGLETON
+                .get_plan()
+                .constraints()
+                .max_non_los_default_alloc_bytes
+        }
+    };
+    let (kind, bump_pointer_offset) = match selector {
+        AllocatorSelector::Immix(_) => (
+            AllocFastpathKind::BumpPointer,
+            offset_of!(ImmixAllocator<JuliaVM>, bump_pointer),
+        ),
+        AllocatorSelector::BumpPointer(_) => (
+            AllocFastpathKind::BumpPointer,
+            offset_of!(BumpAllocator<JuliaVM>, bump_pointer),
+        ),
+        AllocatorSelector::FreeList(_) => (AllocFastpathKind::FreeList, 0),
+        _ => (AllocFastpathKind::None, 0),
+    };
+    let (cursor_offset, limit_offset) = if kind == AllocFastpathKind::BumpPointer {
+        (
+            allocator_offset + bump_pointer_offset + offset_of!(BumpPointer, cursor),
+            allocator_offset + bump_pointer_offset + offset_of!(BumpPointer, limit),
+        )
+    } else {
+        (0, 0)
+    };
+    let (free_lists_offset, free_list_stride, num_size_classes) =
+        if kind == AllocFastpathKind::FreeList {
+            (
+                allocator_offset + offset_of!(FreeListAllocator<JuliaVM>, available_blocks),
+                std::mem::size_of::<BlockList>(),
+                std::mem::size_of::<BlockLists>() / std::mem::size_of::<BlockList>(),
+            )
+        } else {
+            (0, 0, 0)
+        };
+    AllocFastpath {
+        kind,
+        allocator_offset,
+        cursor_offset,
+        limit_offset,
+        free_lists_offset,
+        free_list_stride,
+        num_size_classes,
+        max_size,
+    }
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_gc_init(
     min_heap_size: usize,
@@ -31,6 +213,8 @@ pub extern "C" fn mmtk_gc_init(
         crate::JULIA_HEADER_SIZE = header_size;
         crate::JULIA_BUFF_TAG = buffer_tag;
     };
+    // The heap sizes may be set with the environment variables.
+    let (min_heap_size, max_heap_size) = crate::config::heap_sizes(min_heap_size, max_heap_size);
 
     {
         let mut builder = BUILDER.lock().unwrap();
@@ -50,39 +234,70 @@ pub extern "C" fn mmtk_gc_init(
         };
         if let Some(plan) = force_plan {
             builder.options.plan.set(plan);
+        } else if !crate::config::plan_selected() {
+            // Otherwise, the plan is selected at runtime with the `plan` option or MMTK_PLAN. The default is Immix.
+            builder.options.plan.set(PlanSelector::Immix);
+        } else if !is_supported_plan(*builder.options.plan) {
+            // MMTk core keeps its own default plan if MMTK_PLAN does not parse.
+            warn!(
+                "The plan {:?} is not supported by the Julia binding (supported plans: {:?}). Using Immix.",
+                *builder.options.plan, SUPPORTED_PLANS
+            );
+            builder.options.plan.set(PlanSelector::Immix);
         }
+        let plan = *builder.options.plan;
+        info!("Using the plan {:?}", plan);
 
         // Set heap size
-        let success =
+        use crate::gc_trigger::{default_max_total_memory, JuliaTriggerSelector};
+        use mmtk::util::options::GCTriggerSelector;
+        let trigger = match crate::gc_trigger::selected_trigger() {
             // By default min and max heap size are 0, and we use the Stock GC heuristics
-            if min_heap_size == 0 && max_heap_size == 0 {
-                info!(
-                    "Setting mmtk heap size to use Stock GC heuristics as defined in gc_trigger.rs",
-                );
-                builder
-                    .options
-                    .gc_trigger
-                    .set(mmtk::util::options::GCTriggerSelector::Delegated)
-            } else if min_heap_size != 0 {
-                info!(
-                    "Setting mmtk heap size to a variable size with min-max of {}-{} (in bytes)",
-                    min_heap_size, max_heap_size
-                );
-                builder.options.gc_trigger.set(
-                    mmtk::util::options::GCTriggerSelector::DynamicHeapSize(
-                        min_heap_size,
-                        max_heap_size,
-                    ),
-                )
-            } else {
-                info!(
-                    "Setting mmtk heap size to a fixed max of {} (in bytes)",
-                    max_heap_size
-                );
-                builder.options.gc_trigger.set(
-                    mmtk::util::options::GCTriggerSelector::FixedHeapSize(max_heap_size),
-                )
-            };
+            None if min_heap_size == 0 && max_heap_size == 0 => JuliaTriggerSelector::Stock,
+            None if min_heap_size != 0 => JuliaTriggerSelector::Dynamic,
+            None => JuliaTriggerSelector::Fixed,
+            Some(selected) => selected,
+        };
+        // If a policy is selected without heap sizes, use the defaults.
+        let max_heap_size = if max_heap_size == 0 {
+            default_max_total_memory()
+        } else {
+            max_heap_size
+        };
+        let min_heap_size = if min_heap_size == 0 {
+            crate::gc_trigger::DEFAULT_COLLECT_INTERVAL
+        } else {
+            min_heap_size
+        };
+        crate::gc_trigger::configure_trigger(trigger, min_heap_size, max_heap_size);
+        match trigger {
+            JuliaTriggerSelector::Stock => info!(
+                "Setting mmtk heap size to use Stock GC heuristics as defined in gc_trigger.rs",
+            ),
+            JuliaTriggerSelector::MemBalancer => info!(
+                "Setting mmtk heap size to use MemBalancer with min-max of {}-{} (in bytes) and tradeoff {}",
+                min_heap_size,
+                max_heap_size,
+                crate::gc_trigger::membalancer_tradeoff()
+            ),
+            JuliaTriggerSelector::Pause => info!(
+                "Setting mmtk heap size to target pauses of {} ms with a GC CPU fraction of {}, with min-max of {}-{} (in bytes)",
+                crate::gc_trigger::pause_target(),
+                crate::gc_trigger::gc_cpu_fraction(),
+                min_heap_size,
+                max_heap_size
+            ),
+            JuliaTriggerSelector::Dynamic => info!(
+                "Setting mmtk heap size to use Stock GC heuristics with min-max of {}-{} (in bytes)",
+                min_heap_size, max_heap_size
+            ),
+            JuliaTriggerSelector::Fixed => info!(
+                "Setting mmtk heap size to a fixed max of {} (in bytes)",
+                max_heap_size
+            ),
+        }
+        // All the policies are implemented in the binding, so the heap limits can be changed at runtime.
+        let success = builder.options.gc_trigger.set(GCTriggerSelector::Delegated);
         assert!(
             success,
             "Failed to set heap size to {}-{}",
@@ -95,8 +310,24 @@ pub extern "C" fn mmtk_gc_init(
 
         // Set GC threads
         if n_gcthreads > 0 {
+            if crate::config::threads_selected() {
+                warn!(
+                    "Julia sets {} GC threads, which overrides the threads option ({})",
+                    n_gcthreads, *builder.options.threads
+                );
+            }
             let success = builder.options.threads.set(n_gcthreads);
             assert!(success, "Failed to set GC threads to {}", n_gcthreads);
+        } else if let Some(cpus) = crate::affinity::gc_thread_affinity()
+            .filter(|cpus| !cpus.is_empty() && !crate::config::threads_selected())
+        {
+            // If the GC threads are pinned, use one GC thread for each of their CPUs. Otherwise MMTk chooses, or
+            // uses the `threads` option (or MMTK_THREADS).
+            info!(
+                "Setting GC threads to {} (the number of CPUs for the GC threads)",
+                cpus.len()
+            );
+            builder.options.threads.set(cpus.len());
         }
     }
 
@@ -104,6 +335,14 @@ pub extern "C" fn mmtk_gc_init(
     assert!(!crate::MMTK_INITIALIZED.load(Ordering::SeqCst));
     // Make sure we initialize MMTk here
     lazy_static::initialize(&SINGLETON);
+    PLAN_NEEDS_LOG_BIT.store(
+        SINGLETON.get_plan().constraints().needs_log_bit,
+        Ordering::SeqCst,
+    );
+    PLAN_COLLECTS_GARBAGE.store(
+        SINGLETON.get_plan().constraints().collects_garbage,
+        Ordering::SeqCst,
+    );
 
     unsafe {
         MMTK_SIDE_LOG_BIT_BASE_ADDRESS =
@@ -113,29 +352,52 @@ pub extern "C" fn mmtk_gc_init(
     // Hijack the panic hook to make sure that if we crash in the GC threads, the process aborts.
     crate::set_panic_hook();
 
-    // Assert to make sure our fastpath allocation is correct.
+    // Assert to make sure our fastpath allocation is correct for the selected plan.
     {
         // If the assertion failed, check the allocation fastpath in Julia
         // - runtime fastpath: mmtk_immix_alloc_fast and mmtk_immortal_alloc_fast in julia.h
         // - compiler inserted fastpath: llvm-final-gc-lowering.cpp
+        // The runtime gets the offsets of the allocators from mmtk_get_alloc_fastpath.
         use mmtk::util::alloc::AllocatorSelector;
+        use mmtk::util::options::PlanSelector;
+        let plan = *SINGLETON.get_options().plan;
         let default_allocator = memory_manager::get_allocator_mapping::<JuliaVM>(
             &SINGLETON,
             AllocationSemantics::Default,
         );
-        assert_eq!(default_allocator, AllocatorSelector::Immix(0));
+        let expected_default_allocator = match plan {
+            PlanSelector::Immix | PlanSelector::StickyImmix => AllocatorSelector::Immix(0),
+            PlanSelector::MarkSweep => AllocatorSelector::FreeList(0),
+            PlanSelector::NoGC => AllocatorSelector::BumpPointer(0),
+            _ => unreachable!(),
+        };
+        assert_eq!(
+            default_allocator, expected_default_allocator,
+            "Unexpected default allocator for {:?}",
+            plan
+        );
         let immortal_allocator = memory_manager::get_allocator_mapping::<JuliaVM>(
             &SINGLETON,
             AllocationSemantics::Immortal,
         );
-        assert_eq!(immortal_allocator, AllocatorSelector::BumpPointer(0));
+        assert!(
+            matches!(immortal_allocator, AllocatorSelector::BumpPointer(_)),
+            "Unexpected immortal allocator {:?} for {:?}",
+            immortal_allocator,
+            plan
+        );
     }
 
+    // The large object space is at a different address for each plan.
+    crate::object_model::init_los_range();
+
     // Assert to make sure alignment used in C is correct
     {
         // If the assertion failed, check MMTK_MIN_ALIGNMENT in julia.h
         assert_eq!(<JuliaVM as mmtk::vm::VMBinding>::MIN_ALIGNMENT, 4);
     }
+
+    crate::config::maybe_print_config();
 }
 
 #[no_mangle]
@@ -153,27 +415,29 @@ pub extern "C" fn mmtk_post_bind_mutator(
     mutator: *mut Mutator<JuliaVM>,
     original_box_mutator: *mut Mutator<JuliaVM>,
 ) {
+    // The mutator is bound on its own thread.
+    crate::safepoint::set_current_mutator(unsafe { &*mutator }.mutator_tls.0 .0.to_address());
     // We have to store the original boxed mutator. Otherwise, we may have dangling pointers in mutator.
-    MUTATORS.write().unwrap().insert(
-        Address::from_mut_ptr(mutator),
-        Address::from_mut_ptr(original_box_mutator),
-    );
+    let entry = unsafe { MutatorEntry::new(mutator, original_box_mutator) };
+    crate::alloc_stats::set_current_mutator(&entry);
+    MUTATORS.write().unwrap().insert(entry);
 }
 
 #[no_mangle]
 pub extern "C" fn mmtk_destroy_mutator(mutator: *mut Mutator<JuliaVM>) {
+    // Remove from the registry, and keep its allocation counters
+    let entry = MUTATORS
+        .write()
+        .unwrap()
+        .remove(Address::from_mut_ptr(mutator))
+        .unwrap();
+    crate::alloc_stats::retire(&entry);
+
     // destroy the mutator with MMTk.
     memory_manager::destroy_mutator(unsafe { &mut *mutator });
 
-    let mut mutators = MUTATORS.write().unwrap();
-    let key = Address::from_mut_ptr(mutator);
-
     // Clear the original boxed mutator
-    let orig_mutator = mutators.get(&key).unwrap();
-    let _ = unsafe { Box::from_raw(orig_mutator.to_mut_ptr::<Mutator<JuliaVM>>()) };
-
-    // Remove from our hashmap
-    mutators.remove(&key);
+    let _ = unsafe { Box::from_raw(entry.boxed_mutator.to_mut_ptr::<Mutator<JuliaVM>>()) };
 }
 
 #[no_mangle]
@@ -192,7 +456,7 @@ pub extern "C" fn mmtk_alloc(
         "Alloc size {} is not aligned to min alignment",
         size
     );
-    memory_manager::alloc::<JuliaVM>(unsafe { &mut *mutator }, size, align, offset, semantics)
+    crate::alloc_stats::alloc_and_count(unsafe { &mut *mutator }, size, align, offset, semantics)
 }
 
 #[no_mangle]
@@ -202,7 +466,7 @@ pub extern "C" fn mmtk_alloc_large(
     align: usize,
     offset: usize,
 ) -> Address {
-    memory_manager::alloc::<JuliaVM>(
+    crate::alloc_stats::alloc_and_count(
         unsafe { &mut *mutator },
         size,
         align,
@@ -252,6 +516,30 @@ pub extern "C" fn mmtk_total_bytes() -> usize {
     memory_manager::total_bytes(&SINGLETON)
 }
 
+/// Set the soft heap limit in bytes (0 to unset). GCs will try to keep the heap below this limit.
+#[no_mangle]
+pub extern "C" fn mmtk_set_heap_soft_limit(limit: usize) {
+    crate::gc_trigger::set_heap_soft_limit(limit)
+}
+
+/// Get the soft heap limit in effect (in bytes).
+#[no_mangle]
+pub extern "C" fn mmtk_get_heap_soft_limit() -> usize {
+    crate::gc_trigger::heap_soft_limit()
+}
+
+/// Set the hard heap limit in bytes (0 to use the max heap size given at startup). The heap will not grow beyond this limit.
+#[no_mangle]
+pub extern "C" fn mmtk_set_heap_hard_limit(limit: usize) {
+    crate::gc_trigger::set_heap_hard_limit(limit)
+}
+
+/// Get the hard heap limit in effect (in bytes).
+#[no_mangle]
+pub extern "C" fn mmtk_get_heap_hard_limit() -> usize {
+    crate::gc_trigger::heap_hard_limit()
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_is_live_object(object: ObjectReference) -> bool {
     object.is_live()
@@ -264,6 +552,11 @@ pub extern "C" fn mmtk_is_mapped_address(address: Address) -> bool {
 
 #[no_mangle]
 pub extern "C" fn mmtk_handle_user_collection_request(tls: VMMutatorThread, collection: u8) {
+    // NoGC cannot do a GC
+    if !plan_collects_garbage() {
+        debug!("Ignoring the user collection request: the plan does not collect garbage");
+        return;
+    }
     AtomicIsize::fetch_add(&USER_TRIGGERED_GC, 1, Ordering::SeqCst);
     if AtomicBool::load(&DISABLED_GC, Ordering::SeqCst) {
         AtomicIsize::fetch_add(&USER_TRIGGERED_GC, -1, Ordering::SeqCst);
@@ -306,16 +599,11 @@ pub extern "C" fn mmtk_harness_end(_tls: OpaquePointer) {
     memory_manager::harness_end(&SINGLETON)
 }
 
+/// Set an option of the binding or MMTk core. Return whether the option is set. Use `mmtk_process_option`
+/// to get the reason if it is not set.
 #[no_mangle]
 pub extern "C" fn mmtk_process(name: *const c_char, value: *const c_char) -> bool {
-    let name_str: &CStr = unsafe { CStr::from_ptr(name) };
-    let value_str: &CStr = unsafe { CStr::from_ptr(value) };
-    let mut builder = BUILDER.lock().unwrap();
-    memory_manager::process(
-        &mut builder,
-        name_str.to_str().unwrap(),
-        value_str.to_str().unwrap(),
-    )
+    crate::config::mmtk_process_option(name, value) == crate::config::ConfigError::Ok
 }
 
 #[no_mangle]
@@ -332,11 +620,52 @@ pub extern "C" fn mmtk_last_heap_address() -> Address {
 #[no_mangle]
 pub static JULIA_MALLOC_BYTES: AtomicUsize = AtomicUsize::new(0);
 
+/// The external memory held by Julia objects, as reported by mmtk_adjust_external_memory.
+static EXTERNAL_MEMORY_BYTES: AtomicIsize = AtomicIsize::new(0);
+
+/// The bytes allocated outside the MMTk heap that count toward the heap size: the bytes allocated with
+/// jl_gc_counted_malloc etc. and the external memory.
+pub(crate) fn malloc_and_external_bytes() -> usize {
+    let external = EXTERNAL_MEMORY_BYTES.load(Ordering::SeqCst).max(0) as usize;
+    JULIA_MALLOC_BYTES.load(Ordering::SeqCst) + external
+}
+
+/// Report a change of the external memory held by Julia objects (e.g. buffers owned by a C library), similar to V8's
+/// AdjustAmountOfExternalAllocatedMemory. The external memory counts toward the heap size for GC triggering.
+/// If tls is not NULL and the memory grows, we check whether a GC is needed, and may block the thread for a GC.
+/// Return the total external memory after the change.
+#[no_mangle]
+pub extern "C" fn mmtk_adjust_external_memory(tls: VMMutatorThread, delta: isize) -> usize {
+    let external = EXTERNAL_MEMORY_BYTES.fetch_add(delta, Ordering::SeqCst) + delta;
+    if external < 0 {
+        warn!(
+            "External memory becomes negative ({}) after adjusting by {}",
+            external, delta
+        );
+    }
+    if delta > 0 && !tls.0 .0.is_null() {
+        memory_manager::gc_poll(&SINGLETON, tls);
+    }
+    external.max(0) as usize
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_gc_poll(tls: VMMutatorThread) {
     memory_manager::gc_poll(&SINGLETON, tls);
 }
 
+/// Tell the binding that all the mutators are idle, e.g. a server is waiting for requests. If the heap is close to
+/// the heap target, we collect now instead of pausing the next request. If full_heap is true, a generational plan
+/// does a full heap GC. Idle GCs are rate limited. Return true if a GC was done.
+#[no_mangle]
+pub extern "C" fn mmtk_gc_idle_notification(tls: VMMutatorThread, full_heap: bool) -> bool {
+    if !crate::gc_trigger::request_idle_gc(full_heap) {
+        return false;
+    }
+    memory_manager::gc_poll(&SINGLETON, tls);
+    crate::gc_trigger::take_idle_gc_triggered()
+}
+
 #[no_mangle]
 pub extern "C" fn mmtk_runtime_panic() {
     panic!("Panicking at runtime!")
@@ -354,7 +683,6 @@ pub extern "C" fn mmtk_set_vm_space(start: Address, size: usize) {
     let mmtk_mut: &mut mmtk::MMTK<JuliaVM> = unsafe { std::mem::transmute(mmtk) };
     memory_manager::set_vm_space(mmtk_mut, start, size);
 
-    #[cfg(feature = "stickyimmix")]
     set_side_log_bit_for_region(start, size);
 }
 
@@ -383,14 +711,15 @@ pub extern "C" fn mmtk_memory_region_copy(
 }
 
 #[no_mangle]
-#[allow(unused_variables)] // Args are only used for sticky immix.
 pub extern "C" fn mmtk_immortal_region_post_alloc(start: Address, size: usize) {
-    #[cfg(feature = "stickyimmix")]
     set_side_log_bit_for_region(start, size);
 }
 
-#[cfg(feature = "stickyimmix")]
+/// Set the log bits for a region that is not allocated by MMTk, if the plan uses log bits (e.g. StickyImmix).
 fn set_side_log_bit_for_region(start: Address, size: usize) {
+    if !PLAN_NEEDS_LOG_BIT.load(Ordering::Relaxed) {
+        return;
+    }
     debug!("Bulk set {} to {} ({} bytes)", start, start + size, size);
     use crate::mmtk::vm::ObjectModel;
     match <JuliaVM as mmtk::vm::VMBinding>::VMObjectModel::GLOBAL_LOG_BIT_SPEC.as_spec() {
@@ -405,13 +734,33 @@ pub extern "C" fn mmtk_object_reference_write_post(
     src: ObjectReference,
     target: NullableObjectReference,
 ) {
-    let mutator = unsafe { &mut *mutator };
-    memory_manager::object_reference_write_post(
-        mutator,
-        src,
-        crate::slots::JuliaVMSlot::Simple(mmtk::vm::slot::SimpleSlot::from_address(Address::ZERO)),
-        target.into(),
-    )
+    // This is the post barrier of the object barrier, the only barrier that the supported plans use. Only the slow
+    // path logs the object and adds it to the remembered set, so it is where we count the remembered objects.
+    if object_is_unlogged(src) {
+        mmtk_object_reference_write_slow(unsafe { &mut *mutator }, src, target);
+    }
+}
+
+/// Whether the selected plan uses the log bit. This is set in mmtk_gc_init, so the write barrier does not need to
+/// look up the plan constraints.
+static PLAN_NEEDS_LOG_BIT: AtomicBool = AtomicBool::new(false);
+/// Whether the selected plan collects garbage (i.e. it is not NoGC). This is set in mmtk_gc_init, so the binding does
+/// not need to look up the plan constraints.
+static PLAN_COLLECTS_GARBAGE: AtomicBool = AtomicBool::new(true);
+
+/// Does the selected plan collect garbage?
+pub(crate) fn plan_collects_garbage() -> bool {
+    PLAN_COLLECTS_GARBAGE.load(Ordering::Relaxed)
+}
+
+#[inline(always)]
+fn object_is_unlogged(object: ObjectReference) -> bool {
+    use crate::mmtk::vm::ObjectModel;
+    // The log bit metadata only exists if the plan uses it, e.g. a generational plan.
+    PLAN_NEEDS_LOG_BIT.load(Ordering::Relaxed)
+        && <JuliaVM as mmtk::vm::VMBinding>::VMObjectModel::GLOBAL_LOG_BIT_SPEC
+            .load_atomic::<JuliaVM, u8>(object, None, Ordering::Relaxed)
+            == 1
 }
 
 #[no_mangle]
@@ -421,6 +770,7 @@ pub extern "C" fn mmtk_object_reference_write_slow(
     target: NullableObjectReference,
 ) {
     use mmtk::MutatorContext;
+    crate::gc_trigger::record_remembered_object();
     mutator.barrier().object_reference_write_slow(
         src,
         crate::slots::JuliaVMSlot::Simple(mmtk::vm::slot::SimpleSlot::from_address(Address::ZERO)),
@@ -465,9 +815,18 @@ pub extern "C" fn mmtk_get_obj_size(obj: ObjectReference) -> usize {
     }
 }
 
+#[cfg(all(feature = "object_pinning", not(feature = "non_moving")))]
+fn plan_moves_objects() -> bool {
+    SINGLETON.get_plan().constraints().moves_objects
+}
+
 #[cfg(all(feature = "object_pinning", not(feature = "non_moving")))]
 #[no_mangle]
 pub extern "C" fn mmtk_pin_object(object: ObjectReference) -> bool {
+    // If the plan does not move objects (e.g. MarkSweep and NoGC), pinning is a noop.
+    if !plan_moves_objects() {
+        return false;
+    }
     // We may in the future replace this with a check for the immix space (bound check), which should be much cheaper.
     if mmtk_object_is_managed_by_mmtk(object.to_raw_address().as_usize()) {
         memory_manager::pin_object(object)
@@ -480,6 +839,9 @@ pub extern "C" fn mmtk_pin_object(object: ObjectReference) -> bool {
 #[cfg(all(feature = "object_pinning", not(feature = "non_moving")))]
 #[no_mangle]
 pub extern "C" fn mmtk_unpin_object(object: ObjectReference) -> bool {
+    if !plan_moves_objects() {
+        return false;
+    }
     if mmtk_object_is_managed_by_mmtk(object.to_raw_address().as_usize()) {
         memory_manager::unpin_object(object)
     } else {
@@ -491,6 +853,9 @@ pub extern "C" fn mmtk_unpin_object(object: ObjectReference) -> bool {
 #[cfg(all(feature = "object_pinning", not(feature = "non_moving")))]
 #[no_mangle]
 pub extern "C" fn mmtk_is_pinned(object: ObjectReference) -> bool {
+    if !plan_moves_objects() {
+        return false;
+    }
     if mmtk_object_is_managed_by_mmtk(object.to_raw_address().as_usize()) {
         memory_manager::is_pinned(object)
     } else {
@@ -0,0 +1,224 @@
+// Read the memory limits from cgroups (v1 and v2), so we do not size the heap against the host memory
+// when we run in a container.
+
+use log::*;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often we read the cgroup files again to pick up resizes of the cgroup.
+const RECHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The root of the cgroup file system.
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+/// The last memory limit we read (in bytes). usize::MAX means no limit.
+static CACHED_LIMIT: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+lazy_static! {
+    /// When we read the cgroup files last time. None if we have never read them.
+    static ref LAST_CHECK: Mutex<Option<Instant>> = Mutex::new(None);
+}
+
+/// Get the memory limit of the cgroup of this process (in bytes), or None if there is no limit.
+/// The value is cached, and refreshed at most once per RECHECK_INTERVAL.
+pub fn memory_limit() -> Option<usize> {
+    {
+        let mut last_check = LAST_CHECK.lock().unwrap();
+        let now = Instant::now();
+        if last_check.is_none_or(|last| now.duration_since(last) >= RECHECK_INTERVAL) {
+            *last_check = Some(now);
+            let limit = read_memory_limit().unwrap_or(usize::MAX);
+            let old = CACHED_LIMIT.swap(limit, Ordering::Relaxed);
+            if old != limit {
+                info!("cgroup memory limit changed from {} to {}", old, limit);
+            }
+        }
+    }
+    match CACHED_LIMIT.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        limit => Some(limit),
+    }
+}
+
+/// Apply the cgroup memory limit (if any) to the given memory size.
+pub fn apply_memory_limit(memory: usize) -> usize {
+    match memory_limit() {
+        Some(limit) => std::cmp::min(memory, limit),
+        None => memory,
+    }
+}
+
+/// Read the memory limit from the cgroup files. We use the smallest limit of the cgroup of this process and its ancestors.
+fn read_memory_limit() -> Option<usize> {
+    let proc_cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+    let limit = memory_limit_in(&proc_cgroup, Path::new(CGROUP_ROOT));
+    trace!("cgroup memory limit = {:?}", limit);
+    limit
+}
+
+/// Find the memory limit for the cgroups listed in `proc_cgroup` (in the format of /proc/self/cgroup), with the
+/// cgroup file system mounted at `cgroup_root`.
+fn memory_limit_in(proc_cgroup: &str, cgroup_root: &Path) -> Option<usize> {
+    let mut limit: Option<usize> = None;
+    let mut update = |new: Option<usize>| {
+        if let Some(new) = new {
+            limit = Some(limit.map_or(new, |old| std::cmp::min(old, new)));
+        }
+    };
+
+    // Each line is hierarchy-ID:controller-list:cgroup-path
+    for line in proc_cgroup.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = match (fields.next(), fields.next(), fields.next()) {
+            (Some(id), Some(controllers), Some(path)) => (id, controllers, path),
+            _ => continue,
+        };
+        if id == "0" && controllers.is_empty() {
+            // cgroup v2. In the hybrid mode, the v2 hierarchy is mounted at unified/
+            let unified = cgroup_root.join("unified");
+            let root = if unified.is_dir() {
+                unified
+            } else {
+                cgroup_root.to_path_buf()
+            };
+            for dir in cgroup_dirs(&root, path) {
+                update(read_limit_file(&dir.join("memory.max")));
+                update(read_limit_file(&dir.join("memory.high")));
+            }
+        } else if controllers.split(',').any(|c| c == "memory") {
+            // cgroup v1
+            for dir in cgroup_dirs(&cgroup_root.join("memory"), path) {
+                update(read_limit_file(&dir.join("memory.limit_in_bytes")));
+            }
+        }
+    }
+    limit
+}
+
+/// The cgroup directory for the path and all its ancestors under the root. In a container,
+/// the path may not be visible, in which case we only get the root (which is the container's cgroup).
+fn cgroup_dirs(root: &Path, path: &str) -> Vec<PathBuf> {
+    let mut dirs = vec![];
+    let mut dir = root.join(path.trim_start_matches('/'));
+    while dir.starts_with(root) {
+        if dir.is_dir() {
+            dirs.push(dir.clone());
+        }
+        if !dir.pop() {
+            break;
+        }
+    }
+    dirs
+}
+
+/// Read a limit file. Return None if the file does not exist, or there is no limit.
+fn read_limit_file(file: &Path) -> Option<usize> {
+    let content = std::fs::read_to_string(file).ok()?;
+    parse_limit(&content)
+}
+
+/// Parse the content of a limit file. Return None if there is no limit.
+fn parse_limit(content: &str) -> Option<usize> {
+    let value = content.trim();
+    if value == "max" {
+        return None;
+    }
+    let limit = value.parse::<u64>().ok()?;
+    // cgroup v1 uses a very large number (close to i64::MAX, rounded to pages) for no limit.
+    if limit >= (i64::MAX as u64) / 2 {
+        None
+    } else {
+        Some(limit as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A cgroup file system in a temporary directory
+    struct FakeCgroupRoot(PathBuf);
+
+    impl FakeCgroupRoot {
+        fn new(name: &str) -> Self {
+            let root = std::env::temp_dir().join(format!(
+                "mmtk-julia-cgroup-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = std::fs::remove_dir_all(&root);
+            std::fs::create_dir_all(&root).unwrap();
+            FakeCgroupRoot(root)
+        }
+
+        fn write(&self, file: &str, content: &str) {
+            let file = self.0.join(file);
+            std::fs::create_dir_all(file.parent().unwrap()).unwrap();
+            std::fs::write(file, content).unwrap();
+        }
+    }
+
+    impl Drop for FakeCgroupRoot {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn parse_limit_values() {
+        assert_eq!(parse_limit("1073741824\n"), Some(1 << 30));
+        assert_eq!(parse_limit("max\n"), None);
+        assert_eq!(parse_limit("9223372036854771712\n"), None);
+        assert_eq!(parse_limit(""), None);
+        assert_eq!(parse_limit("garbage"), None);
+    }
+
+    #[test]
+    fn v2_uses_smallest_limit_of_ancestors() {
+        let root = FakeCgroupRoot::new("v2");
+        root.write("memory.max", "max\n");
+        root.write("user.slice/memory.max", "4294967296\n");
+        root.write("user.slice/memory.high", "max\n");
+        root.write("user.slice/app.scope/memory.max", "8589934592\n");
+        root.write("user.slice/app.scope/memory.high", "2147483648\n");
+        assert_eq!(
+            memory_limit_in("0::/user.slice/app.scope\n", &root.0),
+            Some(2147483648)
+        );
+        assert_eq!(
+            memory_limit_in("0::/user.slice\n", &root.0),
+            Some(4294967296)
+        );
+        assert_eq!(memory_limit_in("0::/\n", &root.0), None);
+    }
+
+    #[test]
+    fn v2_hybrid_mode_uses_unified_hierarchy() {
+        let root = FakeCgroupRoot::new("hybrid");
+        root.write("unified/app/memory.max", "1073741824\n");
+        root.write("memory/app/memory.limit_in_bytes", "9223372036854771712\n");
+        let proc_cgroup = "5:memory:/app\n0::/app\n";
+        assert_eq!(memory_limit_in(proc_cgroup, &root.0), Some(1073741824));
+    }
+
+    #[test]
+    fn v1_reads_memory_controller_only() {
+        let root = FakeCgroupRoot::new("v1");
+        root.write("memory/docker/abc/memory.limit_in_bytes", "536870912\n");
+        root.write("cpu/docker/abc/memory.limit_in_bytes", "1024\n");
+        let proc_cgroup = "12:cpu,cpuacct:/docker/abc\n9:memory:/docker/abc\nmalformed\n";
+        assert_eq!(memory_limit_in(proc_cgroup, &root.0), Some(536870912));
+    }
+
+    #[test]
+    fn invisible_path_falls_back_to_root() {
+        let root = FakeCgroupRoot::new("container");
+        root.write("memory.max", "268435456\n");
+        assert_eq!(
+            memory_limit_in("0::/kubepods/pod1/container\n", &root.0),
+            Some(268435456)
+        );
+    }
+}
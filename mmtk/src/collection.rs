@@ -1,10 +1,8 @@
+use crate::mutator_registry::MutatorEntry;
 use crate::SINGLETON;
-use crate::{
-    jl_gc_get_max_memory, jl_gc_prepare_to_collect, jl_gc_update_stats, jl_get_gc_disable_counter,
-    jl_hrtime, jl_throw_out_of_memory_error,
-};
+use crate::{jl_gc_prepare_to_collect, jl_gc_update_stats, jl_get_gc_disable_counter, jl_hrtime};
 use crate::{JuliaVM, USER_TRIGGERED_GC};
-use log::{info, trace};
+use log::{info, trace, warn};
 use mmtk::util::alloc::AllocationError;
 use mmtk::util::heap::GCTriggerPolicy;
 use mmtk::util::opaque_pointer::*;
@@ -15,13 +13,30 @@ use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicU64, Ordering};
 use crate::{BLOCK_FOR_GC, STW_COND, WORLD_HAS_STOPPED};
 
 pub static GC_START: AtomicU64 = AtomicU64::new(0);
+/// Set from the time a GC is requested until the mutators are resumed, so mmtk_gc_shutdown can wait for it.
+static GC_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+
+/// Is a GC requested or in progress?
+pub(crate) fn gc_in_progress() -> bool {
+    GC_IN_PROGRESS.load(Ordering::SeqCst)
+}
+
+/// A GC is requested. This is set before the mutator that requested the GC reaches block_for_gc.
+pub(crate) fn on_gc_requested() {
+    if !GC_IN_PROGRESS.swap(true, Ordering::SeqCst) {
+        // The GC threads run with the normal priority during the GC.
+        crate::affinity::on_gc_start();
+    }
+}
 
 use std::collections::HashSet;
-use std::sync::RwLock;
-use std::thread::ThreadId;
+use std::sync::{Mutex, RwLock};
+use std::thread::{JoinHandle, ThreadId};
 
 lazy_static! {
     static ref GC_THREADS: RwLock<HashSet<ThreadId>> = RwLock::new(HashSet::new());
+    /// The join handles of the GC threads, so we can join them in mmtk_gc_shutdown
+    static ref GC_THREAD_HANDLES: Mutex<Vec<JoinHandle<()>>> = Mutex::new(vec![]);
 }
 
 pub(crate) fn register_gc_thread() {
@@ -45,13 +60,16 @@ impl Collection<JuliaVM> for VMCollection {
         F: FnMut(&'static mut Mutator<JuliaVM>),
     {
         // Wait for all mutators to stop and all finalizers to run
-        while !AtomicBool::load(&WORLD_HAS_STOPPED, Ordering::SeqCst) {
-            // Stay here while the world has not stopped
-            // FIXME add wait var
-        }
+        crate::safepoint::wait_for_world_stopped();
 
         trace!("Stopped the world!");
 
+        // Measure the phases of this GC
+        crate::gc_stats::add_phase_markers();
+
+        // Count the fastpath allocations before the allocators are reset.
+        crate::alloc_stats::on_gc_start();
+
         // Tell MMTk the stacks are ready.
         {
             use mmtk::vm::ActivePlan;
@@ -80,11 +98,22 @@ impl Collection<JuliaVM> for VMCollection {
             )
         }
 
-        AtomicBool::store(&BLOCK_FOR_GC, false, Ordering::SeqCst);
-        AtomicBool::store(&WORLD_HAS_STOPPED, false, Ordering::SeqCst);
+        crate::gc_stats::finish_gc(end);
+        crate::alloc_stats::on_gc_end();
+        crate::safepoint::reset();
+        crate::affinity::on_gc_end();
 
-        let (_, cvar) = &*STW_COND.clone();
+        // Hold the lock so a mutator in mmtk_block_thread_for_gc cannot miss the notification.
+        let (lock, cvar) = &*STW_COND.clone();
+        {
+            let _guard = lock.lock().unwrap();
+            AtomicBool::store(&BLOCK_FOR_GC, false, Ordering::SeqCst);
+            AtomicBool::store(&WORLD_HAS_STOPPED, false, Ordering::SeqCst);
+            GC_IN_PROGRESS.store(false, Ordering::SeqCst);
+        }
         cvar.notify_all();
+        crate::gc_trace::instant("resume mutators");
+        crate::gc_trace::flush();
 
         info!(
             "Live bytes = {}, total bytes = {}",
@@ -98,14 +127,29 @@ impl Collection<JuliaVM> for VMCollection {
     fn block_for_gc(_tls: VMMutatorThread) {
         info!("Triggered GC!");
 
+        // A user request does not go through the GC trigger.
+        on_gc_requested();
+
+        crate::safepoint::request_gc();
         unsafe { jl_gc_prepare_to_collect() };
 
         info!("Finished blocking mutator for GC!");
+
+        // The mutators are resumed. Tell the runtime if the GC saw memory pressure.
+        crate::memory_pressure::deliver_pending();
+        // Tell the runtime if the GC found objects ready for finalization.
+        crate::finalization::deliver_pending();
+
+        // The runtime throws the OutOfMemoryError once the allocation returns. See oom.rs
+        if crate::gc_trigger::take_gc_overhead_limit_exceeded() {
+            info!("GC overhead limit exceeded. Throwing OutOfMemoryError.");
+            crate::oom::set_pending_out_of_memory();
+        }
     }
 
     fn spawn_gc_thread(_tls: VMThread, ctx: GCThreadContext<JuliaVM>) {
-        // Just drop the join handle. The thread will run until the process quits.
-        let _ = std::thread::Builder::new()
+        // Keep the join handle. The thread runs until the process quits, or until mmtk_gc_shutdown stops it.
+        let handle = std::thread::Builder::new()
             .name("MMTk Worker".to_string())
             .spawn(move || {
                 use mmtk::util::opaque_pointer::*;
@@ -113,6 +157,8 @@ impl Collection<JuliaVM> for VMCollection {
 
                 // Remember this GC thread
                 register_gc_thread();
+                crate::affinity::on_gc_thread_start();
+                crate::gc_trace::name_current_thread("MMTk Worker");
 
                 // Start the worker loop
                 let worker_tls = VMWorkerThread(VMThread(OpaquePointer::from_address(unsafe {
@@ -125,19 +171,25 @@ impl Collection<JuliaVM> for VMCollection {
                 }
 
                 // The GC thread quits somehow. Unresgister this GC thread
+                crate::affinity::on_gc_thread_exit();
                 unregister_gc_thread();
-            });
+            })
+            .unwrap();
+        GC_THREAD_HANDLES.lock().unwrap().push(handle);
     }
 
-    fn schedule_finalization(_tls: VMWorkerThread) {}
+    fn schedule_finalization(_tls: VMWorkerThread) {
+        // The runtime is notified after the mutators are resumed (in block_for_gc).
+        crate::finalization::schedule();
+    }
 
-    fn out_of_memory(_tls: VMThread, _err_kind: AllocationError) {
-        println!("Out of Memory!");
-        unsafe { jl_throw_out_of_memory_error() };
+    fn out_of_memory(_tls: VMThread, err_kind: AllocationError) {
+        // MMTk returns a null address for the allocation, and the runtime throws the OutOfMemoryError. See oom.rs
+        crate::oom::handle_out_of_memory(err_kind);
     }
 
     fn vm_live_bytes() -> usize {
-        crate::api::JULIA_MALLOC_BYTES.load(Ordering::SeqCst)
+        crate::api::malloc_and_external_bytes()
     }
 
     fn is_collection_enabled() -> bool {
@@ -145,14 +197,7 @@ impl Collection<JuliaVM> for VMCollection {
     }
 
     fn create_gc_trigger() -> Box<dyn GCTriggerPolicy<JuliaVM>> {
-        use crate::gc_trigger::*;
-        use mmtk::util::os::{OSMemory, OS};
-        // max_memory can be unrealistically large if no heap size hint is set for Julia, e.g. 2PB for 64 bits.
-        // We want to set MMTk's heap size to a more reasonable value.
-        let max_memory = unsafe { jl_gc_get_max_memory() };
-        let total_memory = OS::get_system_total_memory().unwrap() as usize;
-        let heap_size = std::cmp::min(max_memory, total_memory);
-        Box::new(JuliaGCTrigger::new(heap_size))
+        crate::gc_trigger::create_delegated_trigger()
     }
 }
 
@@ -167,12 +212,14 @@ pub fn is_current_gc_nursery() -> bool {
 pub extern "C" fn mmtk_block_thread_for_gc() {
     AtomicBool::store(&BLOCK_FOR_GC, true, Ordering::SeqCst);
 
-    let (lock, cvar) = &*STW_COND.clone();
-    let mut count = lock.lock().unwrap();
-
     info!("Blocking for GC!");
 
-    AtomicBool::store(&WORLD_HAS_STOPPED, true, Ordering::SeqCst);
+    // Register the arrival of this mutator, and wait for the other mutators. This sets WORLD_HAS_STOPPED.
+    // We check BLOCK_FOR_GC with the lock held below, so we will not miss the GC finishing in between.
+    crate::safepoint::arrive();
+
+    let (lock, cvar) = &*STW_COND.clone();
+    let mut count = lock.lock().unwrap();
 
     while AtomicBool::load(&BLOCK_FOR_GC, Ordering::SeqCst) {
         count = cvar.wait(count).unwrap();
@@ -180,3 +227,55 @@ pub extern "C" fn mmtk_block_thread_for_gc() {
 
     AtomicIsize::store(&USER_TRIGGERED_GC, 0, Ordering::SeqCst);
 }
+
+/// Shut down the GC: wait for the GC in flight (if any) to finish, stop and join all the GC threads, and destroy the
+/// mutators that are left. This is terminal: MMTk cannot be initialized again in the process, and no mutator may
+/// allocate or trigger a GC after this (e.g. the runtime is torn down). Return true if the shutdown was clean, i.e. all
+/// the mutators were destroyed before.
+#[no_mangle]
+pub extern "C" fn mmtk_gc_shutdown() -> bool {
+    info!("Shutting down the GC");
+
+    // Wait for the GC in flight, including a GC that is requested but has not stopped the world yet
+    {
+        let (lock, cvar) = &*STW_COND.clone();
+        let _guard = cvar
+            .wait_while(lock.lock().unwrap(), |_| {
+                GC_IN_PROGRESS.load(Ordering::SeqCst)
+            })
+            .unwrap();
+    }
+
+    // Ask the GC threads to exit once they are idle, and join them. This is the same as what we do before forking.
+    // A GC thread cannot fail to join: the panic hook aborts the process if a GC thread panics.
+    SINGLETON.prepare_to_fork();
+    let handles: Vec<JoinHandle<()>> = GC_THREAD_HANDLES.lock().unwrap().drain(..).collect();
+    let n_threads = handles.len();
+    for handle in handles {
+        let _ = handle.join();
+    }
+    info!("Joined {} GC threads", n_threads);
+    crate::gc_trace::close();
+
+    // Report and destroy the mutators that are left
+    let leftover: Vec<MutatorEntry> = crate::MUTATORS.write().unwrap().drain().collect();
+    let clean = leftover.is_empty();
+    if !clean {
+        warn!("{} mutators are not destroyed", leftover.len());
+    }
+    for entry in leftover {
+        warn!(
+            "  mutator {} (thread {}, tls {})",
+            entry.mutator, entry.tid, entry.tls
+        );
+        crate::alloc_stats::retire(&entry);
+        mmtk::memory_manager::destroy_mutator(unsafe { entry.mutator() });
+        let _ = unsafe { Box::from_raw(entry.boxed_mutator.to_mut_ptr::<Mutator<JuliaVM>>()) };
+    }
+
+    info!(
+        "GC shutdown is {}",
+        if clean { "clean" } else { "not clean" }
+    );
+    clean
+}
@@ -0,0 +1,481 @@
+// The configuration of the binding.
+//
+// The binding is configured from several places:
+//   - the arguments of mmtk_gc_init (the heap sizes and the number of GC threads),
+//   - options set with mmtk_process_option (or mmtk_process): the options of the binding listed in BINDING_OPTIONS,
+//     and the options of MMTk core (e.g. `plan` and `threads`),
+//   - the MMTK_* environment variables: each option of the binding has one, MMTk core reads MMTK_<OPTION> for its
+//     own options, and the heap sizes can be set with MMTK_MIN_HSIZE/MMTK_MAX_HSIZE (in MB) or
+//     MMTK_MIN_HSIZE_G/MMTK_MAX_HSIZE_G (in GB),
+//   - cargo features, which can force a plan at build time.
+// An option set with mmtk_process_option overrides the environment variable. The options are validated when they
+// are set, and an invalid value in an environment variable is ignored with a warning.
+//
+// The effective value of an option can be queried with mmtk_get_option, and the effective configuration is printed
+// to stderr at the end of mmtk_gc_init with the `print_config` option (or MMTK_PRINT_CONFIG).
+
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+use crate::{affinity, finalization, gc_trace, gc_trigger, oom, trigger_trace};
+use crate::{BUILDER, MMTK_INITIALIZED};
+use libc::c_char;
+use log::*;
+use std::cell::RefCell;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// The environment variables for the minimum heap size (in MB, or in GB with the _G suffix).
+pub const MIN_HEAP_SIZE_ENV_VAR: &str = "MMTK_MIN_HSIZE";
+pub const MIN_HEAP_SIZE_G_ENV_VAR: &str = "MMTK_MIN_HSIZE_G";
+/// The environment variables for the maximum heap size (in MB, or in GB with the _G suffix).
+pub const MAX_HEAP_SIZE_ENV_VAR: &str = "MMTK_MAX_HSIZE";
+pub const MAX_HEAP_SIZE_G_ENV_VAR: &str = "MMTK_MAX_HSIZE_G";
+/// The environment variable that MMTk core reads for the plan.
+pub const PLAN_ENV_VAR: &str = "MMTK_PLAN";
+/// The environment variable that MMTk core reads for the number of GC threads.
+pub const THREADS_ENV_VAR: &str = "MMTK_THREADS";
+
+/// The name of the option (for `mmtk_process_option`) to print the effective configuration at startup.
+pub const PRINT_CONFIG_OPTION: &str = "print_config";
+/// The environment variable to print the effective configuration at startup.
+pub const PRINT_CONFIG_ENV_VAR: &str = "MMTK_PRINT_CONFIG";
+
+const MB: f64 = (1 << 20) as f64;
+const GB: f64 = (1 << 30) as f64;
+
+/// The result of setting or getting an option. This maps to MMTkConfigError in mmtk.h.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    Ok = 0,
+    /// The name or the value is not a valid UTF-8 string.
+    InvalidString = 1,
+    /// The binding does not have an option with the name. Only returned by mmtk_get_option, as any other
+    /// name is passed to MMTk core when setting an option.
+    UnknownOption = 2,
+    /// The value is not valid for the option.
+    InvalidValue = 3,
+    /// The option can only be set before mmtk_gc_init.
+    AlreadyInitialized = 4,
+    /// MMTk core does not accept the option: either there is no such option, or the value is invalid.
+    RejectedByCore = 5,
+    /// The buffer is too small for the value.
+    BufferTooSmall = 6,
+}
+
+/// An option of the binding
+struct BindingOption {
+    /// The name for mmtk_process_option
+    name: &'static str,
+    /// The environment variable
+    env_var: &'static str,
+    /// The option is only read at startup, and cannot be set after mmtk_gc_init.
+    startup_only: bool,
+    /// Parse and store the value. Return an error message if the value is invalid.
+    set: fn(&str) -> Result<(), String>,
+    /// The effective value
+    value: fn() -> String,
+}
+
+/// The options of the binding. The options are parsed and stored by the modules that use them.
+const BINDING_OPTIONS: &[BindingOption] = &[
+    BindingOption {
+        name: gc_trigger::JULIA_TRIGGER_OPTION,
+        env_var: gc_trigger::JULIA_TRIGGER_ENV_VAR,
+        startup_only: true,
+        set: gc_trigger::set_julia_trigger,
+        value: || match gc_trigger::selected_trigger() {
+            Some(selector) => selector.to_string(),
+            None => "unset".to_string(),
+        },
+    },
+    BindingOption {
+        name: gc_trigger::MEMBALANCER_TRADEOFF_OPTION,
+        env_var: gc_trigger::MEMBALANCER_TRADEOFF_ENV_VAR,
+        startup_only: false,
+        set: gc_trigger::set_membalancer_tradeoff,
+        value: || gc_trigger::membalancer_tradeoff().to_string(),
+    },
+    BindingOption {
+        name: gc_trigger::PAUSE_TARGET_OPTION,
+        env_var: gc_trigger::PAUSE_TARGET_ENV_VAR,
+        startup_only: false,
+        set: gc_trigger::set_pause_target,
+        value: || gc_trigger::pause_target().to_string(),
+    },
+    BindingOption {
+        name: gc_trigger::GC_CPU_FRACTION_OPTION,
+        env_var: gc_trigger::GC_CPU_FRACTION_ENV_VAR,
+        startup_only: false,
+        set: gc_trigger::set_gc_cpu_fraction,
+        value: || gc_trigger::gc_cpu_fraction().to_string(),
+    },
+    BindingOption {
+        name: gc_trigger::GC_OVERHEAD_LIMIT_OPTION,
+        env_var: gc_trigger::GC_OVERHEAD_LIMIT_ENV_VAR,
+        startup_only: false,
+        set: gc_trigger::set_gc_overhead_limit,
+        value: || match gc_trigger::gc_overhead_limit() {
+            Some(limit) => format!(
+                "{},{},{}",
+                limit.gc_time_ratio, limit.heap_free_ratio, limit.cycles
+            ),
+            None => "off".to_string(),
+        },
+    },
+    BindingOption {
+        name: oom::OOM_HEADROOM_OPTION,
+        env_var: oom::OOM_HEADROOM_ENV_VAR,
+        startup_only: false,
+        set: oom::set_oom_headroom,
+        value: || oom::oom_headroom().to_string(),
+    },
+    BindingOption {
+        name: oom::OOM_HEAP_CENSUS_OPTION,
+        env_var: oom::OOM_HEAP_CENSUS_ENV_VAR,
+        startup_only: false,
+        set: oom::set_oom_heap_census,
+        value: || oom::heap_census_enabled().to_string(),
+    },
+    BindingOption {
+        name: affinity::GC_THREAD_AFFINITY_OPTION,
+        env_var: affinity::GC_THREAD_AFFINITY_ENV_VAR,
+        startup_only: true,
+        set: affinity::set_gc_thread_affinity,
+        value: || match affinity::gc_thread_affinity() {
+            Some(cpus) => cpus
+                .iter()
+                .map(|cpu| cpu.to_string())
+                .collect::<Vec<_>>()
+                .join(","),
+            None => "unset".to_string(),
+        },
+    },
+    BindingOption {
+        name: affinity::GC_THREAD_IDLE_PRIORITY_OPTION,
+        env_var: affinity::GC_THREAD_IDLE_PRIORITY_ENV_VAR,
+        startup_only: false,
+        set: affinity::set_gc_thread_idle_priority,
+        value: || affinity::gc_thread_idle_priority().to_string(),
+    },
+    BindingOption {
+        name: gc_trace::GC_TRACE_FILE_OPTION,
+        env_var: gc_trace::GC_TRACE_FILE_ENV_VAR,
+        startup_only: false,
+        set: gc_trace::set_trace_file,
+        value: || gc_trace::trace_file().unwrap_or_else(|| "unset".to_string()),
+    },
+    BindingOption {
+        name: finalization::FINALIZER_THREAD_OPTION,
+        env_var: finalization::FINALIZER_THREAD_ENV_VAR,
+        startup_only: false,
+        set: finalization::set_finalizer_thread,
+        value: || finalization::finalizer_thread_enabled().to_string(),
+    },
+    BindingOption {
+        name: trigger_trace::GC_TRIGGER_TRACE_OPTION,
+        env_var: trigger_trace::GC_TRIGGER_TRACE_ENV_VAR,
+        startup_only: false,
+        set: trigger_trace::set_trace_file,
+        value: || trigger_trace::trace_file().unwrap_or_else(|| "unset".to_string()),
+    },
+    BindingOption {
+        name: PRINT_CONFIG_OPTION,
+        env_var: PRINT_CONFIG_ENV_VAR,
+        startup_only: true,
+        set: |value| parse_bool(value).map(|print| PRINT_CONFIG.store(print, Ordering::Relaxed)),
+        value: || PRINT_CONFIG.load(Ordering::Relaxed).to_string(),
+    },
+];
+
+lazy_static! {
+    static ref PRINT_CONFIG: AtomicBool =
+        AtomicBool::new(read_env_var(PRINT_CONFIG_ENV_VAR, parse_bool).unwrap_or(false));
+}
+
+/// Is the plan selected with the `plan` option?
+static PLAN_SELECTED: AtomicBool = AtomicBool::new(false);
+/// Is the number of GC threads set with the `threads` option?
+static THREADS_SELECTED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// The message for the last error of mmtk_process_option or mmtk_get_option on this thread
+    static LAST_ERROR: RefCell<CString> = RefCell::new(CString::default());
+}
+
+/// Read and validate an environment variable. Return None if it is not set, or if the value is invalid (with a warning).
+pub(crate) fn read_env_var<T>(
+    var: &str,
+    parse: impl FnOnce(&str) -> Result<T, String>,
+) -> Option<T> {
+    let value = std::env::var_os(var)?;
+    let result = match value.to_str() {
+        Some(value) => parse(value),
+        None => Err(format!("{:?} is not a valid UTF-8 string", value)),
+    };
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("Ignoring {}: {}", var, e);
+            None
+        }
+    }
+}
+
+/// Parse a boolean option: true/false, 1/0, yes/no or on/off.
+pub(crate) fn parse_bool(value: &str) -> Result<bool, String> {
+    match value.to_ascii_lowercase().as_str() {
+        "true" | "1" | "yes" | "on" => Ok(true),
+        "false" | "0" | "no" | "off" => Ok(false),
+        _ => Err(format!("Invalid boolean: {} (expect true or false)", value)),
+    }
+}
+
+/// Parse a heap size in MB or GB (which can be a decimal number, e.g. 1.5) to bytes.
+fn parse_heap_size(value: &str, unit: f64) -> Result<usize, String> {
+    match value.trim().parse::<f64>() {
+        Ok(size) if size.is_finite() && size >= 0.0 => Ok((size * unit) as usize),
+        _ => Err(format!(
+            "Invalid heap size: {} (expect a non-negative number)",
+            value
+        )),
+    }
+}
+
+/// The heap size set with the environment variables (in bytes). The size in MB is used if both are set.
+fn heap_size_from_env(mb_var: &str, gb_var: &str) -> Option<usize> {
+    read_env_var(mb_var, |v| parse_heap_size(v, MB))
+        .or_else(|| read_env_var(gb_var, |v| parse_heap_size(v, GB)))
+}
+
+/// The heap sizes to use (in bytes), from the arguments of mmtk_gc_init. If the runtime does not set the heap sizes
+/// (both are 0), they are read from MMTK_MIN_HSIZE(_G) and MMTK_MAX_HSIZE(_G). 0 means the size is not set.
+pub(crate) fn heap_sizes(min_heap_size: usize, max_heap_size: usize) -> (usize, usize) {
+    let (min_heap_size, max_heap_size) = if min_heap_size == 0 && max_heap_size == 0 {
+        (
+            heap_size_from_env(MIN_HEAP_SIZE_ENV_VAR, MIN_HEAP_SIZE_G_ENV_VAR).unwrap_or(0),
+            heap_size_from_env(MAX_HEAP_SIZE_ENV_VAR, MAX_HEAP_SIZE_G_ENV_VAR).unwrap_or(0),
+        )
+    } else {
+        (min_heap_size, max_heap_size)
+    };
+    if max_heap_size != 0 && min_heap_size > max_heap_size {
+        warn!(
+            "The minimum heap size {} is larger than the maximum heap size {}. Using {} for both.",
+            min_heap_size, max_heap_size, max_heap_size
+        );
+        return (max_heap_size, max_heap_size);
+    }
+    (min_heap_size, max_heap_size)
+}
+
+/// Is the plan selected at runtime, with the `plan` option or MMTK_PLAN? MMTk core ignores MMTK_PLAN if it is
+/// not a plan, so it does not count then.
+pub(crate) fn plan_selected() -> bool {
+    PLAN_SELECTED.load(Ordering::SeqCst)
+        || read_env_var(PLAN_ENV_VAR, |v| {
+            v.parse::<mmtk::util::options::PlanSelector>()
+                .map_err(|_| format!("Unknown plan: {}", v))
+        })
+        .is_some()
+}
+
+/// Is the number of GC threads set at runtime, with the `threads` option or MMTK_THREADS? MMTk core ignores
+/// MMTK_THREADS if it is not a positive number, so it does not count then.
+pub(crate) fn threads_selected() -> bool {
+    THREADS_SELECTED.load(Ordering::SeqCst)
+        || read_env_var(THREADS_ENV_VAR, |v| match v.parse::<usize>() {
+            Ok(threads) if threads > 0 => Ok(threads),
+            _ => Err(format!("Invalid threads: {} (expect a positive number)", v)),
+        })
+        .is_some()
+}
+
+/// Set an option of the binding or MMTk core. Return the error and its message if the option is not set.
+pub fn process_option(name: &str, value: &str) -> Result<(), (ConfigError, String)> {
+    let initialized = MMTK_INITIALIZED.load(Ordering::SeqCst);
+    if let Some(option) = BINDING_OPTIONS.iter().find(|option| option.name == name) {
+        if option.startup_only && initialized {
+            return Err((
+                ConfigError::AlreadyInitialized,
+                format!("{} can only be set before mmtk_gc_init", name),
+            ));
+        }
+        return (option.set)(value).map_err(|e| (ConfigError::InvalidValue, e));
+    }
+
+    // Options for MMTk core. MMTk is initialized with the builder lock held (see SINGLETON), so we check again with
+    // the lock held: an option that we set after that would be ignored.
+    let mut builder = BUILDER.lock().unwrap();
+    if MMTK_INITIALIZED.load(Ordering::SeqCst) {
+        return Err((
+            ConfigError::AlreadyInitialized,
+            format!(
+                "The MMTk option {} can only be set before mmtk_gc_init",
+                name
+            ),
+        ));
+    }
+    if !mmtk::memory_manager::process(&mut builder, name, value) {
+        return Err((
+            ConfigError::RejectedByCore,
+            format!(
+                "MMTk does not accept {}={} (unknown option or invalid value)",
+                name, value
+            ),
+        ));
+    }
+    match name {
+        "plan" => PLAN_SELECTED.store(true, Ordering::SeqCst),
+        "threads" => THREADS_SELECTED.store(true, Ordering::SeqCst),
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Get the effective value of an option. The options of MMTk core that the binding sets are also supported:
+/// `plan`, `threads`, `min_heap_size` and `max_heap_size` (in bytes).
+pub fn option_value(name: &str) -> Option<String> {
+    if let Some(option) = BINDING_OPTIONS.iter().find(|option| option.name == name) {
+        return Some((option.value)());
+    }
+    match name {
+        "plan" => Some(format!("{:?}", *BUILDER.lock().unwrap().options.plan)),
+        "threads" => Some((*BUILDER.lock().unwrap().options.threads).to_string()),
+        "min_heap_size" => Some(gc_trigger::heap_size_bounds().0.to_string()),
+        "max_heap_size" => Some(gc_trigger::heap_size_bounds().1.to_string()),
+        _ => None,
+    }
+}
+
+/// Print the effective configuration to stderr.
+pub fn print_config() {
+    eprintln!("[MMTk] Configuration:");
+    for name in ["plan", "threads", "min_heap_size", "max_heap_size"] {
+        eprintln!("  {} = {}", name, option_value(name).unwrap());
+    }
+    eprintln!("  heap_soft_limit = {}", gc_trigger::heap_soft_limit());
+    eprintln!("  heap_hard_limit = {}", gc_trigger::heap_hard_limit());
+    for option in BINDING_OPTIONS {
+        eprintln!(
+            "  {} = {} ({})",
+            option.name,
+            (option.value)(),
+            option.env_var
+        );
+    }
+}
+
+/// Print the effective configuration if it is asked for with the `print_config` option. This is called
+/// at the end of mmtk_gc_init.
+pub(crate) fn maybe_print_config() {
+    if PRINT_CONFIG.load(Ordering::Relaxed) {
+        print_config();
+    }
+}
+
+fn set_last_error(message: &str) {
+    warn!("{}", message);
+    LAST_ERROR.with(|last| {
+        *last.borrow_mut() = CString::new(message.replace('\0', "")).unwrap();
+    });
+}
+
+fn to_str<'a>(s: *const c_char) -> Result<&'a str, ConfigError> {
+    unsafe { CStr::from_ptr(s) }.to_str().map_err(|_| {
+        set_last_error("The option name or value is not a valid UTF-8 string");
+        ConfigError::InvalidString
+    })
+}
+
+/// Set an option of the binding or MMTk core. If the option is not set, the error message can be
+/// retrieved with `mmtk_config_error_message`.
+#[no_mangle]
+pub extern "C" fn mmtk_process_option(name: *const c_char, value: *const c_char) -> ConfigError {
+    let (name, value) = match (to_str(name), to_str(value)) {
+        (Ok(name), Ok(value)) => (name, value),
+        (Err(e), _) | (_, Err(e)) => return e,
+    };
+    match process_option(name, value) {
+        Ok(()) => ConfigError::Ok,
+        Err((e, message)) => {
+            set_last_error(&message);
+            e
+        }
+    }
+}
+
+/// Get the effective value of an option as a NUL terminated string in the buffer.
+#[no_mangle]
+pub extern "C" fn mmtk_get_option(
+    name: *const c_char,
+    buf: *mut c_char,
+    len: usize,
+) -> ConfigError {
+    let name = match to_str(name) {
+        Ok(name) => name,
+        Err(e) => return e,
+    };
+    let value = match option_value(name) {
+        Some(value) => value,
+        None => {
+            set_last_error(&format!("Unknown option {}", name));
+            return ConfigError::UnknownOption;
+        }
+    };
+    if value.len() + 1 > len {
+        set_last_error(&format!(
+            "The value of {} needs {} bytes, but the buffer has {} bytes",
+            name,
+            value.len() + 1,
+            len
+        ));
+        return ConfigError::BufferTooSmall;
+    }
+    unsafe {
+        std::ptr::copy_nonoverlapping(value.as_ptr() as *const c_char, buf, value.len());
+        *buf.add(value.len()) = 0;
+    }
+    ConfigError::Ok
+}
+
+/// The message of the last error of `mmtk_process_option` or `mmtk_get_option` on this thread. The string is
+/// valid until the next call to these functions on this thread.
+#[no_mangle]
+pub extern "C" fn mmtk_config_error_message() -> *const c_char {
+    LAST_ERROR.with(|last| last.borrow().as_ptr())
+}
+
+/// Print the effective configuration to stderr.
+#[no_mangle]
+pub extern "C" fn mmtk_print_config() {
+    print_config();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bool_values() {
+        for value in ["true", "1", "yes", "on", "TRUE", "On"] {
+            assert_eq!(parse_bool(value), Ok(true), "{}", value);
+        }
+        for value in ["false", "0", "no", "off", "False", "OFF"] {
+            assert_eq!(parse_bool(value), Ok(false), "{}", value);
+        }
+        for value in ["", "2", "y", "enabled", " true"] {
+            assert!(parse_bool(value).is_err(), "{}", value);
+        }
+    }
+
+    #[test]
+    fn parse_heap_size_values() {
+        assert_eq!(parse_heap_size("0", MB), Ok(0));
+        assert_eq!(parse_heap_size("512", MB), Ok(512 << 20));
+        assert_eq!(parse_heap_size("1.5", GB), Ok(3 << 29));
+        assert_eq!(parse_heap_size(" 2 ", GB), Ok(2 << 30));
+        for value in ["", "-1", "1MB", "inf", "NaN"] {
+            assert!(parse_heap_size(value, MB).is_err(), "{}", value);
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Deliver finalization work to the runtime.
+//
+// When a GC finds objects that are ready for finalization (they are moved to the to_finalize list in
+// julia_finalizer.rs), we call Collection::schedule_finalization, which marks finalization as scheduled.
+// After the mutators are resumed, we notify the runtime:
+//   - a callback registered with `mmtk_register_finalization_callback` is called on the mutator that triggered the GC,
+//   - if the `finalizer_thread` option (or MMTK_FINALIZER_THREAD) is set, a dedicated finalizer thread is woken up
+//     to run the pending finalizers, so objects that own resources (files, sockets, etc) are finalized promptly
+//     instead of at the next safepoint of a mutator that polls jl_gc_have_pending_finalizers.
+//
+// The finalizer thread is adopted by the Julia runtime (jl_adopt_thread) when it is first needed. It waits for
+// finalization work in a GC safe region, so it never holds up a GC.
+
+use crate::config::{parse_bool, read_env_var};
+use crate::{
+    jl_adopt_thread, jl_gc_run_pending_finalizers, jl_gc_safepoint, jl_get_current_task,
+    jl_get_ptls_states,
+};
+use log::*;
+use std::sync::atomic::{AtomicBool, AtomicI8, Ordering};
+use std::sync::{Condvar, Mutex, Once, RwLock};
+
+/// The name of the option (for `mmtk_process`) to run finalizers on a dedicated thread.
+pub const FINALIZER_THREAD_OPTION: &str = "finalizer_thread";
+/// The environment variable to run finalizers on a dedicated thread.
+pub const FINALIZER_THREAD_ENV_VAR: &str = "MMTK_FINALIZER_THREAD";
+
+/// The callback to notify the runtime that there are objects ready for finalization. It runs on a mutator
+/// after the GC, and may run the finalizers (e.g. with jl_gc_run_pending_finalizers) or schedule a task to do so.
+pub type FinalizationCallback = extern "C" fn();
+
+/// gc_state of a thread in a GC safe region. See JL_GC_STATE_SAFE in julia_threads.h
+pub(crate) const JL_GC_STATE_SAFE: i8 = 2;
+
+lazy_static! {
+    static ref FINALIZER_THREAD: AtomicBool = AtomicBool::new(
+        read_env_var(FINALIZER_THREAD_ENV_VAR, parse_bool).unwrap_or(false)
+    );
+    static ref CALLBACK: RwLock<Option<FinalizationCallback>> = RwLock::new(None);
+    /// Is there finalization work for the finalizer thread?
+    static ref FINALIZER_THREAD_WORK: (Mutex<bool>, Condvar) = (Mutex::new(false), Condvar::new());
+}
+
+/// Set when a GC found objects ready for finalization, and the runtime is not notified yet.
+static FINALIZATION_SCHEDULED: AtomicBool = AtomicBool::new(false);
+static START_FINALIZER_THREAD: Once = Once::new();
+
+/// Set the `finalizer_thread` option. Return an error message if the value is invalid. See config.rs
+pub fn set_finalizer_thread(value: &str) -> Result<(), String> {
+    parse_bool(value)
+        .map(|enabled| FINALIZER_THREAD.store(enabled, Ordering::SeqCst))
+        .map_err(|_| format!("Invalid finalizer_thread: {} (expect true or false)", value))
+}
+
+/// Do we run finalizers on a dedicated thread?
+pub fn finalizer_thread_enabled() -> bool {
+    FINALIZER_THREAD.load(Ordering::SeqCst)
+}
+
+/// A GC found objects ready for finalization. This is called on a GC worker.
+pub(crate) fn schedule() {
+    FINALIZATION_SCHEDULED.store(true, Ordering::SeqCst);
+}
+
+/// Notify the runtime if the last GC scheduled finalization. This is called on a mutator after the GC.
+pub(crate) fn deliver_pending() {
+    if !FINALIZATION_SCHEDULED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    if FINALIZER_THREAD.load(Ordering::SeqCst) {
+        START_FINALIZER_THREAD.call_once(start_finalizer_thread);
+        let (lock, cvar) = &*FINALIZER_THREAD_WORK;
+        *lock.lock().unwrap() = true;
+        cvar.notify_one();
+    }
+    if let Some(callback) = *CALLBACK.read().unwrap() {
+        trace!("Delivering finalization notification");
+        callback();
+    }
+}
+
+/// Register a callback that is called when a GC found objects ready for finalization. A NULL callback unregisters
+/// the current callback.
+#[no_mangle]
+pub extern "C" fn mmtk_register_finalization_callback(callback: Option<FinalizationCallback>) {
+    info!(
+        "{} finalization callback",
+        if callback.is_some() {
+            "Registered"
+        } else {
+            "Unregistered"
+        }
+    );
+    *CALLBACK.write().unwrap() = callback;
+}
+
+fn start_finalizer_thread() {
+    let res = std::thread::Builder::new()
+        .name("MMTk Finalizer".to_string())
+        .spawn(|| {
+            unsafe { jl_adopt_thread() };
+            let ptls = unsafe { &*jl_get_ptls_states() };
+            let gc_state = unsafe { &*(&ptls.gc_state as *const _ as *const AtomicI8) };
+            info!("Started the finalizer thread (tid = {})", ptls.tid);
+
+            let (lock, cvar) = &*FINALIZER_THREAD_WORK;
+            loop {
+                // Wait in a GC safe region, so GCs do not wait for this thread.
+                gc_state.store(JL_GC_STATE_SAFE, Ordering::Release);
+                {
+                    let mut work = cvar
+                        .wait_while(lock.lock().unwrap(), |work| !*work)
+                        .unwrap();
+                    *work = false;
+                }
+                // Leave the GC safe region. If a GC is running, wait for it at the safepoint.
+                gc_state.store(0, Ordering::Release);
+                unsafe { jl_gc_safepoint() };
+
+                trace!("Running pending finalizers on the finalizer thread");
+                unsafe { jl_gc_run_pending_finalizers(jl_get_current_task()) };
+            }
+        });
+    if let Err(e) = res {
+        warn!("Failed to start the finalizer thread: {}", e);
+    }
+}
@@ -0,0 +1,256 @@
+// Measure the time of each phase of a GC, so we can see where the pauses go.
+//
+// The phases are measured in the hooks that MMTk calls in the binding, and with marker work packets that run
+// when a work bucket opens (all the work in the earlier buckets is done by then):
+//   stop_the_world:    from the GC request to the arrival of all the mutators (see safepoint.rs)
+//   mutator_roots:     from the first to the last call of scan_roots_in_mutator_thread (on any worker)
+//   vm_specific_roots: scan_vm_specific_roots
+//   closure:           from the end of root scanning to the opening of the SoftRefClosure bucket
+//   weak_refs:         from SoftRefClosure to VMRefClosure, i.e. MMTk's reference processing
+//   finalizers:        ScanFinalizersSingleThreaded
+//   sweep_vm_specific: SweepVMSpecific
+//   release:           from the opening of the Release bucket to resuming the mutators (includes SweepVMSpecific)
+// The timestamps are from jl_hrtime.
+
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+use crate::jl_hrtime;
+use crate::JuliaVM;
+use crate::SINGLETON;
+use log::*;
+use mmtk::memory_manager;
+use mmtk::scheduler::{GCWork, GCWorker, WorkBucketStage};
+use mmtk::MMTK;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// The events that we record the time for in a GC
+#[derive(Copy, Clone, Debug)]
+pub(crate) enum Event {
+    StopTheWorld,
+    MutatorRoots,
+    VMSpecificRoots,
+    /// The SoftRefClosure bucket opens, i.e. the transitive closure is done.
+    RefClosureStart,
+    /// The VMRefClosure bucket opens, i.e. MMTk's reference processing is done.
+    VMRefClosureStart,
+    Finalizers,
+    SweepVMSpecific,
+    /// The Release bucket opens.
+    ReleaseStart,
+}
+
+const NUM_EVENTS: usize = 8;
+
+impl Event {
+    /// The name of the event in the GC trace (see gc_trace.rs)
+    fn name(self) -> &'static str {
+        match self {
+            Event::StopTheWorld => "stop the world",
+            Event::MutatorRoots => "scan mutator roots",
+            Event::VMSpecificRoots => "scan VM specific roots",
+            Event::RefClosureStart => "ref closure start",
+            Event::VMRefClosureStart => "VM ref closure start",
+            Event::Finalizers => "scan finalizers",
+            Event::SweepVMSpecific => "sweep VM specific",
+            Event::ReleaseStart => "release start",
+        }
+    }
+}
+
+/// The time span of an event. If an event happens more than once (e.g. root scanning on multiple workers),
+/// this is from the earliest start to the latest end.
+struct Span {
+    start: AtomicU64,
+    end: AtomicU64,
+}
+
+impl Span {
+    const fn new() -> Self {
+        Self {
+            start: AtomicU64::new(u64::MAX),
+            end: AtomicU64::new(0),
+        }
+    }
+
+    fn get(&self) -> Option<(u64, u64)> {
+        let start = self.start.load(Ordering::SeqCst);
+        let end = self.end.load(Ordering::SeqCst);
+        if start <= end {
+            Some((start, end))
+        } else {
+            None
+        }
+    }
+
+    fn reset(&self) {
+        self.start.store(u64::MAX, Ordering::SeqCst);
+        self.end.store(0, Ordering::SeqCst);
+    }
+}
+
+static SPANS: [Span; NUM_EVENTS] = [
+    Span::new(),
+    Span::new(),
+    Span::new(),
+    Span::new(),
+    Span::new(),
+    Span::new(),
+    Span::new(),
+    Span::new(),
+];
+
+/// The time spent in each phase of a GC (in nanoseconds). A phase is 0 if we did not see it in the GC.
+/// This is exposed to C with `mmtk_get_last_gc_phase_times`.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, Default)]
+pub struct GCPhaseTimes {
+    /// From the GC request to resuming the mutators
+    pub total: u64,
+    /// The phases below are listed in the order they happen in a GC
+    pub stop_the_world: u64,
+    pub mutator_roots: u64,
+    pub vm_specific_roots: u64,
+    pub closure: u64,
+    pub weak_refs: u64,
+    pub finalizers: u64,
+    pub sweep_vm_specific: u64,
+    pub release: u64,
+}
+
+lazy_static! {
+    static ref LAST_GC_PHASE_TIMES: Mutex<GCPhaseTimes> = Mutex::new(GCPhaseTimes::default());
+}
+
+fn record_span(event: Event, start: u64, end: u64) {
+    let span = &SPANS[event as usize];
+    span.start.fetch_min(start, Ordering::SeqCst);
+    span.end.fetch_max(end, Ordering::SeqCst);
+}
+
+/// Record the span of an event. The event is also recorded in the GC trace on the current thread.
+pub(crate) fn record(event: Event, start: u64, end: u64) {
+    record_span(event, start, end);
+    crate::gc_trace::complete(event.name(), start, end, &[]);
+}
+
+/// Record the span of scanning the roots of a mutator (by its thread id).
+pub(crate) fn record_mutator_roots(mutator_tid: i16, start: u64, end: u64) {
+    record_span(Event::MutatorRoots, start, end);
+    crate::gc_trace::complete(
+        Event::MutatorRoots.name(),
+        start,
+        end,
+        &[("mutator_tid", mutator_tid as i64)],
+    );
+}
+
+/// Record that an event happens now.
+pub(crate) fn mark(event: Event) {
+    let now = unsafe { jl_hrtime() };
+    record_span(event, now, now);
+}
+
+/// Run the closure, and record its time as the event.
+pub(crate) fn time<T>(event: Event, f: impl FnOnce() -> T) -> T {
+    let start = unsafe { jl_hrtime() };
+    let result = f();
+    record(event, start, unsafe { jl_hrtime() });
+    result
+}
+
+/// Add the packets that mark the opening of the work buckets. This is called when the mutators are stopped.
+pub(crate) fn add_phase_markers() {
+    memory_manager::add_work_packet(
+        &SINGLETON,
+        WorkBucketStage::SoftRefClosure,
+        PhaseMarker(Event::RefClosureStart),
+    );
+    memory_manager::add_work_packet(
+        &SINGLETON,
+        WorkBucketStage::Release,
+        PhaseMarker(Event::ReleaseStart),
+    );
+}
+
+/// The GC ends at `end`. Compute the phase times of the GC, and clear the events for the next GC.
+pub(crate) fn finish_gc(end: u64) -> GCPhaseTimes {
+    let span = |event: Event| SPANS[event as usize].get();
+    // The span between two events, or None if either is missing
+    let between = |from: Option<u64>, to: Option<u64>| match (from, to) {
+        (Some(from), Some(to)) => Some((from, std::cmp::max(from, to))),
+        _ => None,
+    };
+    let duration = |span: Option<(u64, u64)>| span.map_or(0, |(start, end)| end - start);
+
+    // The GC request, or the earliest event we saw if we do not know the request
+    let first = SPANS
+        .iter()
+        .filter_map(|span| span.get())
+        .map(|(start, _)| start)
+        .min();
+    let roots_end = [Event::MutatorRoots, Event::VMSpecificRoots]
+        .iter()
+        .filter_map(|e| span(*e).map(|(_, end)| end))
+        .max();
+    let ref_closure = span(Event::RefClosureStart).map(|(start, _)| start);
+    let vm_ref_closure = span(Event::VMRefClosureStart).map(|(start, _)| start);
+    let release = span(Event::ReleaseStart).map(|(start, _)| start);
+
+    let gc = between(first, Some(end));
+    let phases = [
+        ("stop the world", span(Event::StopTheWorld)),
+        ("mutator roots", span(Event::MutatorRoots)),
+        ("VM specific roots", span(Event::VMSpecificRoots)),
+        ("closure", between(roots_end, ref_closure)),
+        ("weak refs", between(ref_closure, vm_ref_closure)),
+        ("finalizers", span(Event::Finalizers)),
+        ("sweep VM specific", span(Event::SweepVMSpecific)),
+        ("release", between(release, Some(end))),
+    ];
+    let times = GCPhaseTimes {
+        total: duration(gc),
+        stop_the_world: duration(phases[0].1),
+        mutator_roots: duration(phases[1].1),
+        vm_specific_roots: duration(phases[2].1),
+        closure: duration(phases[3].1),
+        weak_refs: duration(phases[4].1),
+        finalizers: duration(phases[5].1),
+        sweep_vm_specific: duration(phases[6].1),
+        release: duration(phases[7].1),
+    };
+    if let Some((start, end)) = gc {
+        crate::gc_trace::gc_phase("GC", start, end);
+    }
+    for (name, phase) in phases.iter() {
+        if let Some((start, end)) = phase {
+            crate::gc_trace::gc_phase(name, *start, *end);
+        }
+    }
+    info!("GC phase times (ns): {:?}", times);
+
+    for span in SPANS.iter() {
+        span.reset();
+    }
+    *LAST_GC_PHASE_TIMES.lock().unwrap() = times;
+    times
+}
+
+/// A work packet that records the time when its bucket opens
+struct PhaseMarker(Event);
+
+impl GCWork<JuliaVM> for PhaseMarker {
+    fn do_work(&mut self, _worker: &mut GCWorker<JuliaVM>, _mmtk: &'static MMTK<JuliaVM>) {
+        mark(self.0);
+    }
+}
+
+/// The phase times of the last GC
+pub(crate) fn last_gc_phase_times() -> GCPhaseTimes {
+    *LAST_GC_PHASE_TIMES.lock().unwrap()
+}
+
+/// Get the phase times of the last GC.
+#[no_mangle]
+pub extern "C" fn mmtk_get_last_gc_phase_times(times: *mut GCPhaseTimes) {
+    unsafe { times.write(last_gc_phase_times()) };
+}
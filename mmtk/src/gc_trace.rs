@@ -0,0 +1,210 @@
+// Export GC events in the Chrome trace event format, so GC activity can be viewed next to application traces
+// in Perfetto (https://ui.perfetto.dev) or chrome://tracing.
+//
+// Set the `gc_trace_file` option (or MMTK_GC_TRACE_FILE) to a file path to record a trace. We record:
+//   - the span of each GC and each GC phase (as measured in gc_stats.rs) on a separate "GC" track,
+//   - stop-the-world, root scanning for each mutator, VM specific root scanning, finalizer scanning and
+//     SweepVMSpecific on the thread that does the work,
+//   - an instant event when the mutators are resumed.
+// The timestamps are from jl_hrtime, the same clock as the rest of the binding. The file is a JSON array of events.
+// We flush the file after each GC. The trace is closed when the GC shuts down (mmtk_gc_shutdown) or the process exits,
+// and the viewers accept a trace without the closing bracket if the process is killed.
+
+use crate::config::read_env_var;
+use crate::jl_hrtime;
+use log::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, Once};
+
+/// The name of the option (for `mmtk_process`) for the path of the trace file.
+pub const GC_TRACE_FILE_OPTION: &str = "gc_trace_file";
+/// The environment variable for the path of the trace file.
+pub const GC_TRACE_FILE_ENV_VAR: &str = "MMTK_GC_TRACE_FILE";
+
+/// The tid of the track for GCs and GC phases.
+const GC_TRACK_TID: usize = 0;
+
+/// Set if TRACE_WRITER is Some, so we can check it for each event without taking the lock
+static TRACE_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Register the exit hook that closes the trace once
+static REGISTER_EXIT_HOOK: Once = Once::new();
+
+lazy_static! {
+    static ref TRACE_WRITER: Mutex<Option<BufWriter<File>>> = {
+        let writer = read_env_var(GC_TRACE_FILE_ENV_VAR, open_trace_file);
+        TRACE_ENABLED.store(writer.is_some(), Ordering::Relaxed);
+        Mutex::new(writer)
+    };
+    /// The path of the trace file that we record to
+    static ref TRACE_FILE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Set the `gc_trace_file` option. Return an error message if the trace file cannot be created. See config.rs
+pub fn set_trace_file(value: &str) -> Result<(), String> {
+    // Keep recording to the current trace if we cannot open the new one.
+    let writer = open_trace_file(value)?;
+    let mut guard = TRACE_WRITER.lock().unwrap();
+    if let Some(mut old) = guard.take() {
+        finish(&mut old);
+    }
+    set_writer(&mut guard, Some(writer));
+    Ok(())
+}
+
+fn open_trace_file(path: &str) -> Result<BufWriter<File>, String> {
+    let mut writer = match File::create(path) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => return Err(format!("Failed to create GC trace {}: {}", path, e)),
+    };
+    // Name the process and the GC track.
+    let pid = std::process::id();
+    let res = write!(
+        writer,
+        "[\n{{\"name\":\"process_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"julia\"}}}},\n\
+         {{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"GC\"}}}}",
+        pid, GC_TRACK_TID, pid, GC_TRACK_TID
+    );
+    match res {
+        Ok(()) => {
+            info!("Recording GC trace to {}", path);
+            *TRACE_FILE.lock().unwrap() = Some(path.to_string());
+            REGISTER_EXIT_HOOK.call_once(|| unsafe {
+                libc::atexit(close_at_exit);
+            });
+            Ok(writer)
+        }
+        Err(e) => Err(format!("Failed to write GC trace {}: {}", path, e)),
+    }
+}
+
+/// The path of the trace file. None if we are not recording a trace.
+pub fn trace_file() -> Option<String> {
+    if !enabled() {
+        return None;
+    }
+    TRACE_FILE.lock().unwrap().clone()
+}
+
+/// Are we recording a trace?
+pub(crate) fn enabled() -> bool {
+    // Read the environment variable if we have not.
+    lazy_static::initialize(&TRACE_WRITER);
+    TRACE_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Replace the writer (with the lock of TRACE_WRITER held), and update TRACE_ENABLED.
+fn set_writer(guard: &mut Option<BufWriter<File>>, writer: Option<BufWriter<File>>) {
+    *guard = writer;
+    TRACE_ENABLED.store(guard.is_some(), Ordering::Relaxed);
+}
+
+/// The tid of the current thread in the trace. This is the kernel thread id, as in other traces of the process
+/// (e.g. perf).
+fn current_tid() -> usize {
+    unsafe { libc::gettid() as usize }
+}
+
+fn write_event(event: std::fmt::Arguments) {
+    let mut writer = TRACE_WRITER.lock().unwrap();
+    if let Some(w) = writer.as_mut() {
+        if let Err(e) = w.write_fmt(format_args!(",\n{}", event)) {
+            warn!("Failed to write GC trace: {}. Stop tracing.", e);
+            set_writer(&mut writer, None);
+        }
+    }
+}
+
+/// Format the arguments of an event as a JSON object.
+fn format_args_json(args: &[(&str, i64)]) -> String {
+    let args: Vec<String> = args
+        .iter()
+        .map(|(key, value)| format!("\"{}\":{}", key, value))
+        .collect();
+    format!("{{{}}}", args.join(","))
+}
+
+/// Record an event from `start` to `end` (jl_hrtime) on a track.
+fn complete_on(tid: usize, name: &str, start: u64, end: u64, args: &[(&str, i64)]) {
+    write_event(format_args!(
+        "{{\"name\":\"{}\",\"cat\":\"gc\",\"ph\":\"X\",\"pid\":{},\"tid\":{},\"ts\":{:.3},\"dur\":{:.3},\"args\":{}}}",
+        name,
+        std::process::id(),
+        tid,
+        start as f64 / 1000f64,
+        end.saturating_sub(start) as f64 / 1000f64,
+        format_args_json(args)
+    ));
+}
+
+/// Record an event from `start` to `end` (jl_hrtime) on the current thread.
+pub(crate) fn complete(name: &str, start: u64, end: u64, args: &[(&str, i64)]) {
+    if enabled() {
+        complete_on(current_tid(), name, start, end, args);
+    }
+}
+
+/// Record an event from `start` to `end` (jl_hrtime) on the GC track.
+pub(crate) fn gc_phase(name: &str, start: u64, end: u64) {
+    if enabled() {
+        complete_on(GC_TRACK_TID, name, start, end, &[]);
+    }
+}
+
+/// Name the current thread in the trace.
+pub(crate) fn name_current_thread(name: &str) {
+    if enabled() {
+        write_event(format_args!(
+            "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":{},\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+            std::process::id(),
+            current_tid(),
+            name
+        ));
+    }
+}
+
+/// Record an event that happens now on the current thread.
+pub(crate) fn instant(name: &str) {
+    if enabled() {
+        write_event(format_args!(
+            "{{\"name\":\"{}\",\"cat\":\"gc\",\"ph\":\"i\",\"s\":\"p\",\"pid\":{},\"tid\":{},\"ts\":{:.3}}}",
+            name,
+            std::process::id(),
+            current_tid(),
+            unsafe { jl_hrtime() } as f64 / 1000f64
+        ));
+    }
+}
+
+/// Flush the trace to the file.
+pub(crate) fn flush() {
+    let mut writer = TRACE_WRITER.lock().unwrap();
+    if let Some(w) = writer.as_mut() {
+        if let Err(e) = w.flush() {
+            warn!("Failed to flush GC trace: {}. Stop tracing.", e);
+            set_writer(&mut writer, None);
+        }
+    }
+}
+
+/// Close the trace. This writes the closing bracket. Events after this are not recorded.
+pub(crate) fn close() {
+    let mut writer = TRACE_WRITER.lock().unwrap();
+    if let Some(mut w) = writer.take() {
+        TRACE_ENABLED.store(false, Ordering::Relaxed);
+        finish(&mut w);
+    }
+}
+
+/// Write the closing bracket of a trace, and flush it.
+fn finish(w: &mut BufWriter<File>) {
+    if let Err(e) = writeln!(w, "\n]").and_then(|_| w.flush()) {
+        warn!("Failed to close GC trace: {}", e);
+    }
+}
+
+extern "C" fn close_at_exit() {
+    close();
+}
@@ -6,16 +6,631 @@ use mmtk::util::heap::GCTriggerPolicy;
 use mmtk::util::heap::SpaceStats;
 use mmtk::MMTK;
 
+use crate::config::read_env_var;
+use crate::trigger_trace::{self, TriggerEvent, TriggerInputs};
 use crate::JuliaVM;
 
+use std::fmt;
+use std::str::FromStr;
 use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicU8;
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering;
+use std::sync::Mutex;
 
-use crate::jl_hrtime;
+use crate::jl_gc_get_max_memory;
 
-const DEFAULT_COLLECT_INTERVAL: usize = 5600 * 1024 * std::mem::size_of::<usize>();
+pub(crate) const DEFAULT_COLLECT_INTERVAL: usize = 5600 * 1024 * std::mem::size_of::<usize>();
 const GC_ALWAYS_SWEEP_FULL: bool = false;
+/// If the bytes promoted since the last full heap GC exceed this fraction of the heap size
+/// after that full heap GC, we force a full heap GC.
+const LARGE_PROMOTION_RATE: f64 = 0.5;
+
+/// The number of objects that the object barrier added to the remembered set since the last GC.
+/// This is incremented by the barrier slow path in api.rs (only once for each object that is logged), and consumed at
+/// a GC start.
+pub(crate) static REMSET_OBJECTS: AtomicUsize = AtomicUsize::new(0);
+
+pub(crate) fn record_remembered_object() {
+    REMSET_OBJECTS.fetch_add(1, Ordering::Relaxed);
+}
+
+/// The name of the option (for `mmtk_process`) to select a GC trigger policy.
+pub const JULIA_TRIGGER_OPTION: &str = "julia_trigger";
+/// The environment variable to select a GC trigger policy, if it is not set with `mmtk_process`.
+pub const JULIA_TRIGGER_ENV_VAR: &str = "MMTK_JULIA_TRIGGER";
+
+/// The GC trigger policies that can be selected at startup.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum JuliaTriggerSelector {
+    /// Julia-style heuristics (JuliaGCTrigger)
+    Stock,
+    /// A fixed heap size (FixedHeapSizeTrigger)
+    Fixed,
+    /// A heap size that changes up to a maximum size, with the Julia-style heuristics (JuliaGCTrigger)
+    Dynamic,
+    /// The MemBalancer square-root rule with a tunable memory/time tradeoff (MemBalancerTrigger)
+    MemBalancer,
+    /// Keep the pauses under a target with a bounded GC CPU fraction (PauseTargetTrigger)
+    Pause,
+}
+
+impl FromStr for JuliaTriggerSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "stock" => Ok(Self::Stock),
+            "fixed" => Ok(Self::Fixed),
+            "dynamic" => Ok(Self::Dynamic),
+            "membalancer" => Ok(Self::MemBalancer),
+            "pause" => Ok(Self::Pause),
+            _ => Err(format!("Unknown GC trigger policy: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for JuliaTriggerSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Self::Stock => "stock",
+            Self::Fixed => "fixed",
+            Self::Dynamic => "dynamic",
+            Self::MemBalancer => "membalancer",
+            Self::Pause => "pause",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// The name of the option (for `mmtk_process`) to set the memory/time tradeoff of MemBalancerTrigger.
+pub const MEMBALANCER_TRADEOFF_OPTION: &str = "membalancer_tradeoff";
+/// The environment variable to set the memory/time tradeoff of MemBalancerTrigger.
+pub const MEMBALANCER_TRADEOFF_ENV_VAR: &str = "MMTK_MEMBALANCER_TRADEOFF";
+/// The default memory/time tradeoff of MemBalancerTrigger (per MB).
+pub const DEFAULT_MEMBALANCER_TRADEOFF: f64 = 0.01;
+
+/// The name of the option (for `mmtk_process`) to set the pause target of PauseTargetTrigger (in milliseconds).
+pub const PAUSE_TARGET_OPTION: &str = "pause_target";
+/// The environment variable to set the pause target of PauseTargetTrigger (in milliseconds).
+pub const PAUSE_TARGET_ENV_VAR: &str = "MMTK_PAUSE_TARGET";
+/// The default pause target of PauseTargetTrigger (in milliseconds).
+pub const DEFAULT_PAUSE_TARGET: f64 = 10.0;
+/// The name of the option (for `mmtk_process`) to set the target fraction of CPU time spent in GC for PauseTargetTrigger.
+pub const GC_CPU_FRACTION_OPTION: &str = "gc_cpu_fraction";
+/// The environment variable to set the target fraction of CPU time spent in GC for PauseTargetTrigger.
+pub const GC_CPU_FRACTION_ENV_VAR: &str = "MMTK_GC_CPU_FRACTION";
+/// The default target fraction of CPU time spent in GC for PauseTargetTrigger.
+pub const DEFAULT_GC_CPU_FRACTION: f64 = 0.1;
+
+/// The name of the option (for `mmtk_process`) to enable the GC overhead limit.
+pub const GC_OVERHEAD_LIMIT_OPTION: &str = "gc_overhead_limit";
+/// The environment variable to enable the GC overhead limit.
+pub const GC_OVERHEAD_LIMIT_ENV_VAR: &str = "MMTK_GC_OVERHEAD_LIMIT";
+
+/// The GC overhead limit, similar to the JVM's UseGCOverheadLimit. If GCs keep taking most of the time and
+/// recovering little of the heap, we throw an OutOfMemoryError instead of letting the process crawl.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct GCOverheadLimit {
+    /// The fraction of time spent in GC (GC time / (GC time + mutator time)) above which a GC counts toward the limit
+    pub gc_time_ratio: f64,
+    /// The fraction of the heap recovered by a GC below which the GC counts toward the limit
+    pub heap_free_ratio: f64,
+    /// The number of consecutive GCs that need to count toward the limit before we throw
+    pub cycles: usize,
+}
+
+impl Default for GCOverheadLimit {
+    // The JVM defaults: GCTimeLimit=98, GCHeapFreeLimit=2, and 5 consecutive GCs.
+    fn default() -> Self {
+        Self {
+            gc_time_ratio: 0.98,
+            heap_free_ratio: 0.02,
+            cycles: 5,
+        }
+    }
+}
+
+impl FromStr for GCOverheadLimit {
+    type Err = String;
+
+    /// Either "on" for the defaults, or "<gc_time_ratio>,<heap_free_ratio>,<cycles>", e.g. "0.98,0.02,5".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let err = || {
+            format!(
+                "Invalid GC overhead limit: {} (expect on, off, or <gc_time_ratio>,<heap_free_ratio>,<cycles>)",
+                s
+            )
+        };
+        if s == "on" {
+            return Ok(Self::default());
+        }
+        let fields: Vec<&str> = s.split(',').collect();
+        if fields.len() != 3 {
+            return Err(err());
+        }
+        let ratio = |field: &str| match field.parse::<f64>() {
+            Ok(ratio) if (0.0..=1.0).contains(&ratio) => Ok(ratio),
+            _ => Err(err()),
+        };
+        Ok(Self {
+            gc_time_ratio: ratio(fields[0])?,
+            heap_free_ratio: ratio(fields[1])?,
+            cycles: match fields[2].parse::<usize>() {
+                Ok(cycles) if cycles > 0 => cycles,
+                _ => return Err(err()),
+            },
+        })
+    }
+}
+
+fn parse_gc_overhead_limit(value: &str) -> Result<Option<GCOverheadLimit>, String> {
+    match value {
+        "off" => Ok(None),
+        _ => value.parse::<GCOverheadLimit>().map(Some),
+    }
+}
+
+/// Set at the end of a GC if the GC overhead limit is exceeded. The mutator throws an OutOfMemoryError after the GC.
+static GC_OVERHEAD_LIMIT_EXCEEDED: AtomicBool = AtomicBool::new(false);
+
+lazy_static! {
+    /// The selected policy. The environment variable is only read (and warned about) once.
+    static ref JULIA_TRIGGER: Mutex<Option<JuliaTriggerSelector>> = Mutex::new(
+        read_env_var(JULIA_TRIGGER_ENV_VAR, |v| v.parse::<JuliaTriggerSelector>())
+    );
+    /// The memory/time tradeoff for MemBalancerTrigger (f64 bits). It can be changed at any time, and will be used in the next GC.
+    static ref MEMBALANCER_TRADEOFF: AtomicU64 = AtomicU64::new(
+        read_env_var(MEMBALANCER_TRADEOFF_ENV_VAR, parse_membalancer_tradeoff)
+            .unwrap_or(DEFAULT_MEMBALANCER_TRADEOFF)
+            .to_bits()
+    );
+    /// The pause target (in milliseconds) and the GC CPU fraction for PauseTargetTrigger (f64 bits).
+    /// They can be changed at any time, and will be used in the next GC.
+    static ref PAUSE_TARGET: AtomicU64 = AtomicU64::new(
+        read_env_var(PAUSE_TARGET_ENV_VAR, parse_pause_target)
+            .unwrap_or(DEFAULT_PAUSE_TARGET)
+            .to_bits()
+    );
+    static ref GC_CPU_FRACTION: AtomicU64 = AtomicU64::new(
+        read_env_var(GC_CPU_FRACTION_ENV_VAR, parse_gc_cpu_fraction)
+            .unwrap_or(DEFAULT_GC_CPU_FRACTION)
+            .to_bits()
+    );
+    /// The GC overhead limit. None if it is disabled (default).
+    static ref GC_OVERHEAD_LIMIT: Mutex<Option<GCOverheadLimit>> = Mutex::new(
+        read_env_var(GC_OVERHEAD_LIMIT_ENV_VAR, parse_gc_overhead_limit).flatten()
+    );
+}
+
+/// The heap size bounds used to create the GC trigger policy (in bytes)
+static MIN_HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+static MAX_HEAP_SIZE: AtomicUsize = AtomicUsize::new(0);
+
+/// The heap limits set at runtime with `mmtk_set_heap_soft_limit` and `mmtk_set_heap_hard_limit` (in bytes).
+/// 0 means the limit is not set, and the policy uses the heap size given at startup.
+static HEAP_SOFT_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static HEAP_HARD_LIMIT: AtomicUsize = AtomicUsize::new(0);
+/// Set when a limit is lowered below the current heap size, so the next GC poll triggers a GC.
+static HEAP_LIMIT_LOWERED: AtomicBool = AtomicBool::new(false);
+/// The heap target of the policy (in bytes), updated at the end of each GC
+static HEAP_TARGET: AtomicUsize = AtomicUsize::new(0);
+
+/// During idle time, we collect if the heap size reaches this fraction of the heap target.
+const IDLE_GC_HEAP_RATIO: f64 = 0.75;
+/// The minimal interval between two idle GCs (in nanoseconds), so an idle process does not keep collecting.
+const MIN_IDLE_GC_INTERVAL: u64 = 5_000_000_000;
+/// The idle GC requested by `mmtk_gc_idle_notification` for the next GC poll.
+const IDLE_GC_NONE: u8 = 0;
+const IDLE_GC_AUTO: u8 = 1;
+const IDLE_GC_FULL_HEAP: u8 = 2;
+static IDLE_GC_REQUEST: AtomicU8 = AtomicU8::new(IDLE_GC_NONE);
+/// Set if the idle GC request triggered a GC.
+static IDLE_GC_TRIGGERED: AtomicBool = AtomicBool::new(false);
+/// When the last idle GC was triggered (jl_hrtime)
+static LAST_IDLE_GC: AtomicU64 = AtomicU64::new(0);
+
+/// Set the `julia_trigger` option. Return an error message if the value is invalid. See config.rs
+pub fn set_julia_trigger(value: &str) -> Result<(), String> {
+    value
+        .parse::<JuliaTriggerSelector>()
+        .map(|selector| *JULIA_TRIGGER.lock().unwrap() = Some(selector))
+}
+
+/// Set the `membalancer_tradeoff` option. Return an error message if the value is invalid. See config.rs
+pub fn set_membalancer_tradeoff(value: &str) -> Result<(), String> {
+    parse_membalancer_tradeoff(value)
+        .map(|tradeoff| MEMBALANCER_TRADEOFF.store(tradeoff.to_bits(), Ordering::Relaxed))
+}
+
+/// Set the `pause_target` option. Return an error message if the value is invalid. See config.rs
+pub fn set_pause_target(value: &str) -> Result<(), String> {
+    parse_pause_target(value).map(|target| PAUSE_TARGET.store(target.to_bits(), Ordering::Relaxed))
+}
+
+/// Set the `gc_cpu_fraction` option. Return an error message if the value is invalid. See config.rs
+pub fn set_gc_cpu_fraction(value: &str) -> Result<(), String> {
+    parse_gc_cpu_fraction(value)
+        .map(|fraction| GC_CPU_FRACTION.store(fraction.to_bits(), Ordering::Relaxed))
+}
+
+/// Set the `gc_overhead_limit` option. Return an error message if the value is invalid. See config.rs
+pub fn set_gc_overhead_limit(value: &str) -> Result<(), String> {
+    parse_gc_overhead_limit(value).map(|limit| *GC_OVERHEAD_LIMIT.lock().unwrap() = limit)
+}
+
+fn parse_membalancer_tradeoff(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(tradeoff) if tradeoff.is_finite() && tradeoff > 0.0 => Ok(tradeoff),
+        _ => Err(format!(
+            "Invalid MemBalancer tradeoff: {} (expect a positive number)",
+            value
+        )),
+    }
+}
+
+/// Get the current memory/time tradeoff for MemBalancerTrigger.
+pub fn membalancer_tradeoff() -> f64 {
+    f64::from_bits(MEMBALANCER_TRADEOFF.load(Ordering::Relaxed))
+}
+
+fn parse_pause_target(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(target) if target.is_finite() && target > 0.0 => Ok(target),
+        _ => Err(format!(
+            "Invalid pause target: {} (expect a positive number of milliseconds)",
+            value
+        )),
+    }
+}
+
+fn parse_gc_cpu_fraction(value: &str) -> Result<f64, String> {
+    match value.parse::<f64>() {
+        Ok(fraction) if fraction > 0.0 && fraction < 1.0 => Ok(fraction),
+        _ => Err(format!(
+            "Invalid GC CPU fraction: {} (expect a number between 0 and 1)",
+            value
+        )),
+    }
+}
+
+/// Get the current pause target for PauseTargetTrigger (in milliseconds).
+pub fn pause_target() -> f64 {
+    f64::from_bits(PAUSE_TARGET.load(Ordering::Relaxed))
+}
+
+/// Get the current target fraction of CPU time spent in GC for PauseTargetTrigger.
+pub fn gc_cpu_fraction() -> f64 {
+    f64::from_bits(GC_CPU_FRACTION.load(Ordering::Relaxed))
+}
+
+/// Get the GC overhead limit, or None if it is disabled.
+pub fn gc_overhead_limit() -> Option<GCOverheadLimit> {
+    *GC_OVERHEAD_LIMIT.lock().unwrap()
+}
+
+/// Return true if the last GC exceeded the GC overhead limit, and clear the flag. The caller should throw
+/// an OutOfMemoryError.
+pub(crate) fn take_gc_overhead_limit_exceeded() -> bool {
+    GC_OVERHEAD_LIMIT_EXCEEDED.swap(false, Ordering::SeqCst)
+}
+
+/// Remember the selected policy and its heap size bounds. They are used later when MMTk asks
+/// for the delegated GC trigger in `create_gc_trigger`.
+pub fn configure_trigger(
+    selector: JuliaTriggerSelector,
+    min_heap_size: usize,
+    max_heap_size: usize,
+) {
+    *JULIA_TRIGGER.lock().unwrap() = Some(selector);
+    MIN_HEAP_SIZE.store(min_heap_size, Ordering::Relaxed);
+    MAX_HEAP_SIZE.store(max_heap_size, Ordering::Relaxed);
+}
+
+/// The heap size bounds of the policy (in bytes), as configured in mmtk_gc_init.
+pub fn heap_size_bounds() -> (usize, usize) {
+    (
+        MIN_HEAP_SIZE.load(Ordering::Relaxed),
+        MAX_HEAP_SIZE.load(Ordering::Relaxed),
+    )
+}
+
+/// Create the GC trigger policy that is implemented in the binding (delegated from MMTk).
+pub fn create_delegated_trigger() -> Box<dyn GCTriggerPolicy<JuliaVM>> {
+    let min_heap_size = MIN_HEAP_SIZE.load(Ordering::Relaxed);
+    let max_heap_size = MAX_HEAP_SIZE.load(Ordering::Relaxed);
+    // In a container, the cgroup limit may be smaller than the max heap size.
+    let max_heap_size = crate::cgroup::apply_memory_limit(max_heap_size);
+    match selected_trigger() {
+        Some(JuliaTriggerSelector::Stock) | None => recorded(JuliaGCTrigger::new(max_heap_size)),
+        Some(JuliaTriggerSelector::Dynamic) => recorded(JuliaGCTrigger::with_min_heap_size(
+            std::cmp::min(min_heap_size, max_heap_size),
+            max_heap_size,
+        )),
+        Some(JuliaTriggerSelector::Fixed) => recorded(FixedHeapSizeTrigger::new(max_heap_size)),
+        Some(JuliaTriggerSelector::MemBalancer) => {
+            recorded(MemBalancerTrigger::new(min_heap_size, max_heap_size))
+        }
+        Some(JuliaTriggerSelector::Pause) => {
+            recorded(PauseTargetTrigger::new(min_heap_size, max_heap_size))
+        }
+    }
+}
+
+fn recorded<T: ReplayableTrigger + 'static>(policy: T) -> Box<dyn GCTriggerPolicy<JuliaVM>> {
+    HEAP_TARGET.store(policy.heap_target(), Ordering::Relaxed);
+    Box::new(RecordedTrigger(policy))
+}
+
+/// The heap target of the policy (in bytes), as of the end of the last GC
+pub(crate) fn current_heap_target() -> usize {
+    HEAP_TARGET.load(Ordering::Relaxed)
+}
+
+/// Set the soft heap limit (in bytes). 0 unsets the limit.
+pub fn set_heap_soft_limit(limit: usize) {
+    HEAP_SOFT_LIMIT.store(limit, Ordering::SeqCst);
+    check_lowered_heap_limit(limit);
+}
+
+/// Set the hard heap limit (in bytes). 0 unsets the limit.
+pub fn set_heap_hard_limit(limit: usize) {
+    HEAP_HARD_LIMIT.store(limit, Ordering::SeqCst);
+    check_lowered_heap_limit(limit);
+}
+
+/// If the new limit is below the current heap size, we need a GC at the next poll.
+fn check_lowered_heap_limit(limit: usize) {
+    if limit == 0 || !crate::MMTK_INITIALIZED.load(Ordering::SeqCst) {
+        return;
+    }
+    let heap_size = conversions::pages_to_bytes(crate::SINGLETON.get_plan().get_reserved_pages())
+        + crate::api::malloc_and_external_bytes();
+    if heap_size > limit {
+        info!(
+            "Heap limit {} is below the current heap size {}. Trigger a GC.",
+            limit, heap_size
+        );
+        HEAP_LIMIT_LOWERED.store(true, Ordering::SeqCst);
+    }
+}
+
+/// All the mutators are idle. Ask the next GC poll to collect early if the heap is close to the heap target.
+/// Return false if we had an idle GC recently, and should not collect again.
+pub(crate) fn request_idle_gc(full_heap: bool) -> bool {
+    let last = LAST_IDLE_GC.load(Ordering::SeqCst);
+    if last != 0 && unsafe { crate::jl_hrtime() } < last + MIN_IDLE_GC_INTERVAL {
+        trace!("Skip idle GC: the last idle GC was at {}", last);
+        return false;
+    }
+    IDLE_GC_TRIGGERED.store(false, Ordering::SeqCst);
+    IDLE_GC_REQUEST.store(
+        if full_heap {
+            IDLE_GC_FULL_HEAP
+        } else {
+            IDLE_GC_AUTO
+        },
+        Ordering::SeqCst,
+    );
+    true
+}
+
+/// Return true if the last idle GC request triggered a GC, and clear the request.
+pub(crate) fn take_idle_gc_triggered() -> bool {
+    IDLE_GC_REQUEST.store(IDLE_GC_NONE, Ordering::SeqCst);
+    IDLE_GC_TRIGGERED.swap(false, Ordering::SeqCst)
+}
+
+/// The soft heap limit set at runtime, or None if it is not set.
+pub(crate) fn runtime_heap_soft_limit() -> Option<usize> {
+    match HEAP_SOFT_LIMIT.load(Ordering::SeqCst) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// The hard heap limit set at runtime, or None if it is not set.
+pub(crate) fn runtime_heap_hard_limit() -> Option<usize> {
+    match HEAP_HARD_LIMIT.load(Ordering::SeqCst) {
+        0 => None,
+        limit => Some(limit),
+    }
+}
+
+/// The hard heap limit in effect (in bytes): the limit set at runtime or the max heap size given at startup,
+/// bounded by the cgroup limit.
+pub fn heap_hard_limit() -> usize {
+    let limit = runtime_heap_hard_limit().unwrap_or_else(|| MAX_HEAP_SIZE.load(Ordering::Relaxed));
+    crate::cgroup::apply_memory_limit(limit)
+}
+
+/// The soft heap limit in effect (in bytes). If it is not set at runtime, the stock heuristics use 80% of
+/// the hard limit, and the other policies use the hard limit.
+pub fn heap_soft_limit() -> usize {
+    let hard_limit = heap_hard_limit();
+    match runtime_heap_soft_limit() {
+        Some(limit) => std::cmp::min(limit, hard_limit),
+        None => match selected_trigger() {
+            Some(JuliaTriggerSelector::Stock) | Some(JuliaTriggerSelector::Dynamic) | None => {
+                hard_limit * 80 / 100
+            }
+            Some(_) => hard_limit,
+        },
+    }
+}
+
+/// Get the selected GC trigger policy, either set with `mmtk_process` or with the environment variable.
+/// Return None if no policy is selected.
+pub fn selected_trigger() -> Option<JuliaTriggerSelector> {
+    *JULIA_TRIGGER.lock().unwrap()
+}
+
+/// The default upper bound of the heap size.
+pub fn default_max_total_memory() -> usize {
+    use mmtk::util::os::{OSMemory, OS};
+    // max_memory can be unrealistically large if no heap size hint is set for Julia, e.g. 2PB for 64 bits.
+    // We want to set MMTk's heap size to a more reasonable value.
+    let max_memory = unsafe { jl_gc_get_max_memory() };
+    let total_memory = OS::get_system_total_memory().unwrap() as usize;
+    // In a container, the cgroup limit may be much smaller than the system memory.
+    crate::cgroup::apply_memory_limit(std::cmp::min(max_memory, total_memory))
+}
+
+/// The GC trigger policies in the binding make their decisions only based on [`TriggerInputs`], instead of
+/// querying MMTk and Julia directly. This allows us to record the inputs in a trace, and replay the trace
+/// through any of the policies with a simulated clock (see [`crate::trigger_trace`]).
+pub trait ReplayableTrigger: Send + Sync {
+    /// A GC is starting.
+    fn gc_start(&self, inputs: &TriggerInputs);
+    /// A GC has finished. Return true if the next GC should be a full heap GC.
+    fn gc_end(&self, inputs: &TriggerInputs) -> bool;
+    /// MMTk failed to allocate the given number of pages, and will trigger a GC.
+    fn pending_allocation(&self, pages: usize);
+    /// Is the heap full with the given reserved pages and the bytes allocated outside the MMTk heap?
+    fn heap_full(&self, reserved_pages: usize, malloc_bytes: usize) -> bool;
+    /// The heap size at which we will trigger a GC (in bytes)
+    fn heap_target(&self) -> usize;
+    /// The upper bound of the heap size (in bytes)
+    fn max_heap_size(&self) -> usize;
+    /// Can the heap size grow?
+    fn can_heap_size_grow(&self) -> bool {
+        true
+    }
+    /// Did the policy detect thrashing at the end of the last GC?
+    fn is_thrashing(&self) -> bool {
+        false
+    }
+    /// Did the last GC exceed the GC overhead limit?
+    fn gc_overhead_limit_exceeded(&self) -> bool {
+        false
+    }
+    /// All the mutators are idle. Should we collect now? By default, we collect if the heap is close to the heap target,
+    /// so we are unlikely to need a GC soon after the mutators become busy again.
+    fn idle_gc_required(&self, reserved_pages: usize, malloc_bytes: usize) -> bool {
+        let heap_size = conversions::pages_to_bytes(reserved_pages) + malloc_bytes;
+        heap_size as f64 >= self.heap_target() as f64 * IDLE_GC_HEAP_RATIO
+    }
+}
+
+/// This implements GCTriggerPolicy for a replayable policy. It gathers the inputs from MMTk and Julia,
+/// records them if tracing is enabled, and passes them to the policy.
+pub struct RecordedTrigger<T: ReplayableTrigger>(pub T);
+
+impl<T: ReplayableTrigger> GCTriggerPolicy<JuliaVM> for RecordedTrigger<T> {
+    fn on_gc_start(&self, mmtk: &'static MMTK<JuliaVM>) {
+        // This GC collects for the lowered heap limit. The policy picks up the new limit at the end of the GC.
+        HEAP_LIMIT_LOWERED.store(false, Ordering::SeqCst);
+        let inputs = TriggerInputs::at_gc_start(mmtk);
+        trigger_trace::record(&TriggerEvent::GCStart(inputs));
+        self.0.gc_start(&inputs);
+    }
+
+    fn on_gc_end(&self, mmtk: &'static MMTK<JuliaVM>) {
+        let inputs = TriggerInputs::at_gc_end(mmtk);
+        trigger_trace::record(&TriggerEvent::GCEnd(inputs));
+        if self.0.gc_end(&inputs) {
+            if let Some(gen) = mmtk.get_plan().generational() {
+                // Force full heap in the next GC
+                gen.force_full_heap_collection();
+            }
+        }
+        if self.0.gc_overhead_limit_exceeded() {
+            GC_OVERHEAD_LIMIT_EXCEEDED.store(true, Ordering::SeqCst);
+        }
+        crate::memory_pressure::on_gc_end(
+            conversions::pages_to_bytes(inputs.reserved_pages) + inputs.malloc_bytes,
+            self.0.max_heap_size(),
+            self.0.is_thrashing(),
+        );
+        crate::oom::maybe_restore_oom_headroom(
+            conversions::pages_to_bytes(inputs.reserved_pages) + inputs.malloc_bytes,
+            self.0.max_heap_size(),
+        );
+        crate::oom::maybe_take_heap_census(
+            conversions::pages_to_bytes(inputs.reserved_pages) + inputs.malloc_bytes,
+            self.0.max_heap_size(),
+        );
+        HEAP_TARGET.store(self.0.heap_target(), Ordering::Relaxed);
+    }
+
+    fn on_pending_allocation(&self, pages: usize) {
+        trigger_trace::record(&TriggerEvent::PendingAllocation { pages });
+        self.0.pending_allocation(pages);
+    }
+
+    /// Is a GC required now?
+    fn is_gc_required(
+        &self,
+        space_full: bool,
+        space: Option<SpaceStats<JuliaVM>>,
+        plan: &dyn Plan<VM = JuliaVM>,
+    ) -> bool {
+        // The plan cannot do a GC (NoGC). We check the heap against the hard limit instead.
+        if !plan.constraints().collects_garbage {
+            crate::oom::check_heap_without_gc(plan.get_reserved_pages());
+            return false;
+        }
+        let required = self.is_heap_full(plan) || plan.collection_required(space_full, space);
+        if required {
+            // MMTk requests a GC when this returns true.
+            crate::collection::on_gc_requested();
+        }
+        required
+    }
+
+    /// Is current heap full?
+    fn is_heap_full(&self, plan: &dyn Plan<VM = JuliaVM>) -> bool {
+        // A heap limit was lowered below the heap size. This stays set until a GC starts.
+        if HEAP_LIMIT_LOWERED.load(Ordering::SeqCst) {
+            return true;
+        }
+        let idle_gc = IDLE_GC_REQUEST.swap(IDLE_GC_NONE, Ordering::SeqCst);
+        if idle_gc != IDLE_GC_NONE
+            && self.0.idle_gc_required(
+                plan.get_reserved_pages(),
+                crate::api::malloc_and_external_bytes(),
+            )
+        {
+            info!("Idle GC (full heap = {})", idle_gc == IDLE_GC_FULL_HEAP);
+            if idle_gc == IDLE_GC_FULL_HEAP {
+                if let Some(gen) = plan.generational() {
+                    gen.force_full_heap_collection();
+                }
+            }
+            LAST_IDLE_GC.store(unsafe { crate::jl_hrtime() }, Ordering::SeqCst);
+            IDLE_GC_TRIGGERED.store(true, Ordering::SeqCst);
+            return true;
+        }
+        let malloc_bytes = crate::api::malloc_and_external_bytes();
+        if self.0.heap_full(plan.get_reserved_pages(), malloc_bytes) {
+            return true;
+        }
+        // The heap would eat into the OOM headroom
+        let headroom = crate::oom::held_oom_headroom();
+        headroom != 0
+            && conversions::pages_to_bytes(plan.get_reserved_pages()) + malloc_bytes + headroom
+                >= self.0.max_heap_size()
+    }
+
+    /// Return the current heap size (in pages)
+    fn get_current_heap_size_in_pages(&self) -> usize {
+        self.0.heap_target() / BYTES_IN_PAGE
+    }
+
+    /// Return the upper bound of heap size. The OOM headroom is held back from it until we run out of memory.
+    fn get_max_heap_size_in_pages(&self) -> usize {
+        self.0
+            .max_heap_size()
+            .saturating_sub(crate::oom::held_oom_headroom())
+            / BYTES_IN_PAGE
+    }
+
+    /// Can the heap size grow?
+    fn can_heap_size_grow(&self) -> bool {
+        self.0.can_heap_size_grow()
+    }
+}
 
 /// This tries to implement Julia-style GC triggering heuristics.
 /// Note that for generational GC, Julia may trigger a full sweep collection for the following reasons:
@@ -24,12 +639,21 @@ const GC_ALWAYS_SWEEP_FULL: bool = false;
 /// 3. FULL_SWEEP_REASON_USER_MAX_EXCEEDED: i.e. heap_size > user_max
 /// 4. FULL_SWEEP_REASON_LARGE_PROMOTION_RATE: using the size of the remembered set (promotion rate).
 ///
-/// We support the reasons 1, 2, and 3. However, we do not support 4, since MMTk does not collect
-/// promotion rate information about remembered sets.
+/// We support all the reasons above. MMTk does not collect promotion information, so for 4 we estimate
+/// the promoted bytes from the growth of the MMTk heap across nursery GCs (StickyImmix does not move nursery
+/// objects; malloc'd and external memory is not promoted, so it is left out), and the remembered set volume from the number of objects logged by the object barrier slow path.
 /// For Immix, we obviously always do full heap collections.
 pub struct JuliaGCTrigger {
     heap_target: AtomicUsize,
+    /// The heap target never goes below this (in bytes). It is DEFAULT_COLLECT_INTERVAL for the stock heuristics, and
+    /// the min heap size for the dynamic policy.
+    min_heap_size: usize,
+    /// The max heap size given when the trigger was created. max_total_memory may differ due to the heap limits set
+    /// at runtime or the cgroup limit.
+    configured_max_total_memory: usize,
     max_total_memory: AtomicUsize,
+    /// Is a hard heap limit set at runtime, as of the end of the last GC?
+    hard_limit_set: AtomicBool,
     old_pause_time: AtomicUsize,
     old_mut_time: AtomicUsize,
     old_heap_size: AtomicUsize,
@@ -45,14 +669,36 @@ pub struct JuliaGCTrigger {
     /// The number of pending allocation pages. The allocation requests for them have failed, and a GC is triggered.
     /// We will need to take them into consideration so that the new heap size can accomodate those allocations.
     pending_pages: AtomicUsize,
+    /// The MMTk heap size after the last GC, without the malloc'd memory (in bytes)
+    old_mmtk_heap_size: AtomicUsize,
+    /// The bytes promoted by the last nursery GC (in bytes)
+    promoted_bytes: AtomicUsize,
+    /// The bytes promoted by nursery GCs since the last full heap GC (in bytes)
+    promoted_since_full: AtomicUsize,
+    /// The MMTk heap size after the last full heap GC, without the malloc'd memory (in bytes)
+    live_after_full: AtomicUsize,
+    /// The number of objects that were added to the remembered set before the current GC
+    remset_objects: AtomicUsize,
+    /// The number of consecutive GCs that spent too much time and recovered too little heap (see GCOverheadLimit)
+    overhead_cycles: AtomicUsize,
+    /// Did the last GC exceed the GC overhead limit?
+    overhead_limit_exceeded: AtomicBool,
 }
 
 impl JuliaGCTrigger {
     pub fn new(max_total_mem: usize) -> Self {
+        Self::with_min_heap_size(DEFAULT_COLLECT_INTERVAL, max_total_mem)
+    }
+
+    /// The stock heuristics with a heap size between min_heap_size and max_total_mem (the `dynamic` policy).
+    pub fn with_min_heap_size(min_heap_size: usize, max_total_mem: usize) -> Self {
         // values as defined in gc-stock.c
         Self {
-            heap_target: AtomicUsize::new(DEFAULT_COLLECT_INTERVAL),
+            heap_target: AtomicUsize::new(min_heap_size),
+            min_heap_size,
+            configured_max_total_memory: max_total_mem,
             max_total_memory: AtomicUsize::new(max_total_mem),
+            hard_limit_set: AtomicBool::new(false),
             old_pause_time: AtomicUsize::new(1e7 as usize),
             old_mut_time: AtomicUsize::new(1e9 as usize),
             old_heap_size: AtomicUsize::new(0),
@@ -66,17 +712,23 @@ impl JuliaGCTrigger {
             before_free_heap_size: AtomicUsize::new(0),
             prev_sweep_full: AtomicBool::new(true),
             pending_pages: AtomicUsize::new(0),
+            old_mmtk_heap_size: AtomicUsize::new(0),
+            promoted_bytes: AtomicUsize::new(0),
+            promoted_since_full: AtomicUsize::new(0),
+            live_after_full: AtomicUsize::new(0),
+            remset_objects: AtomicUsize::new(0),
+            overhead_cycles: AtomicUsize::new(0),
+            overhead_limit_exceeded: AtomicBool::new(false),
         }
     }
 }
 
-impl GCTriggerPolicy<JuliaVM> for JuliaGCTrigger {
-    fn on_gc_start(&self, mmtk: &'static MMTK<JuliaVM>) {
+impl ReplayableTrigger for JuliaGCTrigger {
+    fn gc_start(&self, inputs: &TriggerInputs) {
         // reserved pages now should include pending allocations
-        let reserved_pages_now =
-            mmtk.get_plan().get_reserved_pages() + self.pending_pages.load(Ordering::SeqCst);
+        let reserved_pages_now = inputs.reserved_pages + self.pending_pages.load(Ordering::SeqCst);
 
-        let now = unsafe { jl_hrtime() };
+        let now = inputs.time;
         self.gc_start_time.store(now as usize, Ordering::Relaxed);
 
         self.mutator_time.store(
@@ -89,52 +741,86 @@ impl GCTriggerPolicy<JuliaVM> for JuliaGCTrigger {
             Ordering::Relaxed,
         );
         self.before_free_heap_size.store(
-            conversions::pages_to_bytes(reserved_pages_now),
+            conversions::pages_to_bytes(reserved_pages_now) + inputs.malloc_bytes,
             Ordering::Relaxed,
         );
 
-        self.prev_sweep_full.store(
-            if let Some(gen) = mmtk.get_plan().generational() {
-                gen.last_collection_full_heap()
-            } else {
-                false
-            },
-            Ordering::Relaxed,
-        );
+        self.prev_sweep_full
+            .store(inputs.full_heap.unwrap_or(false), Ordering::Relaxed);
+        self.remset_objects
+            .store(inputs.remset_objects, Ordering::Relaxed);
         info!(
             "On GC start: \
             reserved_pages_now = {}, \
             gc_start_time = {}, \
             mutator_time = {}, \
             before_free_heap_size = {}, \
-            prev_sweep_full = {}",
+            prev_sweep_full = {}, \
+            remset_objects = {}",
             reserved_pages_now,
             self.gc_start_time.load(Ordering::Relaxed),
             self.mutator_time.load(Ordering::Relaxed),
             self.before_free_heap_size.load(Ordering::Relaxed),
             self.prev_sweep_full.load(Ordering::Relaxed),
+            self.remset_objects.load(Ordering::Relaxed),
         );
     }
-    fn on_gc_end(&self, mmtk: &'static MMTK<JuliaVM>) {
+    fn gc_end(&self, inputs: &TriggerInputs) -> bool {
         // note that we get the end time at this point but the actual end time
         // is recorded at collection::VMCollection::resume_mutators
-        let gc_end_time = unsafe { jl_hrtime() };
+        let gc_end_time = inputs.time;
         let pause = gc_end_time as usize - self.gc_start_time.load(Ordering::Relaxed);
         self.gc_end_time
             .store(gc_end_time as usize, Ordering::Relaxed);
 
-        let reserved_pages_now =
-            mmtk.get_plan().get_reserved_pages() + self.pending_pages.load(Ordering::SeqCst);
-        let heap_size = conversions::pages_to_bytes(reserved_pages_now);
+        let reserved_pages_now = inputs.reserved_pages + self.pending_pages.load(Ordering::SeqCst);
+        let mmtk_heap_size = conversions::pages_to_bytes(reserved_pages_now);
+        let heap_size = mmtk_heap_size + inputs.malloc_bytes;
+
+        let max_total_memory =
+            inputs.update_max_heap_size(self.configured_max_total_memory, &self.max_total_memory);
+        self.hard_limit_set
+            .store(inputs.hard_limit != usize::MAX, Ordering::Relaxed);
+        let user_max = inputs
+            .soft_limit(max_total_memory)
+            .unwrap_or(max_total_memory * 80 / 100);
+        // malloc'd memory can be freed by the mutator or by finalizers, so the heap size may shrink without a GC.
+        let alloc_diff = self
+            .before_free_heap_size
+            .load(Ordering::Relaxed)
+            .saturating_sub(self.old_heap_size.load(Ordering::Relaxed));
+        let freed_diff = self
+            .before_free_heap_size
+            .load(Ordering::Relaxed)
+            .saturating_sub(heap_size);
 
-        let user_max = self.max_total_memory.load(Ordering::Relaxed) * 80 / 100;
-        let alloc_diff = self.before_free_heap_size.load(Ordering::Relaxed)
-            - self.old_heap_size.load(Ordering::Relaxed);
-        let freed_diff = self.before_free_heap_size.load(Ordering::Relaxed) - heap_size;
+        // Nursery objects are not moved, so whatever the MMTk heap grew by since the last GC survived the nursery GC.
+        let is_nursery = inputs.full_heap == Some(false);
+        if is_nursery {
+            let promoted =
+                mmtk_heap_size.saturating_sub(self.old_mmtk_heap_size.load(Ordering::Relaxed));
+            self.promoted_bytes.store(promoted, Ordering::Relaxed);
+            self.promoted_since_full
+                .fetch_add(promoted, Ordering::Relaxed);
+        } else {
+            self.promoted_bytes.store(0, Ordering::Relaxed);
+            self.promoted_since_full.store(0, Ordering::Relaxed);
+            self.live_after_full
+                .store(mmtk_heap_size, Ordering::Relaxed);
+        }
+        info!(
+            "On GC end: nursery = {}, promoted_bytes = {}, promoted_since_full = {}, live_after_full = {}",
+            is_nursery,
+            self.promoted_bytes.load(Ordering::Relaxed),
+            self.promoted_since_full.load(Ordering::Relaxed),
+            self.live_after_full.load(Ordering::Relaxed),
+        );
         self.old_heap_size.store(heap_size, Ordering::Relaxed);
+        self.old_mmtk_heap_size
+            .store(mmtk_heap_size, Ordering::Relaxed);
 
         // update the heap target only if the user did not force a GC
-        let gc_auto = !mmtk.is_user_triggered_collection();
+        let gc_auto = !inputs.user_triggered;
         if gc_auto {
             let mut target_allocs: f64 = 0.0;
             const ALLOC_SMOOTH_FACTOR: f64 = 0.95;
@@ -175,6 +861,29 @@ impl GCTriggerPolicy<JuliaVM> for JuliaGCTrigger {
                 self.thrash_counter.fetch_sub(1, Ordering::Relaxed);
             }
 
+            // GC overhead limit: too much time in GC, and too little heap recovered, for a number of GCs in a row
+            self.overhead_limit_exceeded.store(false, Ordering::Relaxed);
+            if let Some(limit) = gc_overhead_limit() {
+                let mutator_time = self.mutator_time.load(Ordering::Relaxed);
+                let gc_time_ratio = pause as f64 / (pause + mutator_time) as f64;
+                let heap_free_ratio = freed_diff as f64
+                    / self.before_free_heap_size.load(Ordering::Relaxed).max(1) as f64;
+                if gc_time_ratio > limit.gc_time_ratio && heap_free_ratio < limit.heap_free_ratio {
+                    let cycles = self.overhead_cycles.fetch_add(1, Ordering::Relaxed) + 1;
+                    info!(
+                        "GC overhead: gc_time_ratio = {}, heap_free_ratio = {}, cycles = {}",
+                        gc_time_ratio, heap_free_ratio, cycles
+                    );
+                    if cycles >= limit.cycles {
+                        warn!("GC overhead limit exceeded");
+                        self.overhead_limit_exceeded.store(true, Ordering::Relaxed);
+                        self.overhead_cycles.store(0, Ordering::Relaxed);
+                    }
+                } else {
+                    self.overhead_cycles.store(0, Ordering::Relaxed);
+                }
+            }
+
             if alloc_mem != 0 && alloc_time != 0 && gc_mem != 0 && gc_time != 0 {
                 let alloc_rate = alloc_mem as f64 / alloc_time as f64;
                 let gc_rate = gc_mem as f64 / gc_time as f64;
@@ -249,16 +958,25 @@ impl GCTriggerPolicy<JuliaVM> for JuliaGCTrigger {
             }
 
             target_heap = target_allocs + heap_size as f64;
-            if target_heap < DEFAULT_COLLECT_INTERVAL as f64 {
-                target_heap = DEFAULT_COLLECT_INTERVAL as f64;
+            if target_heap < self.min_heap_size as f64 {
+                target_heap = self.min_heap_size as f64;
                 info!(
-                    "Target heap set to default interval, target_heap = {}",
+                    "Target heap set to the min heap size, target_heap = {}",
                     target_heap
                 );
             }
             self.heap_target
                 .store(target_heap as usize, Ordering::Relaxed);
         }
+        // The guardrails above may push the target past the hard limit, and a user triggered GC keeps the old
+        // target, which may be above a lowered limit.
+        if self.heap_target.load(Ordering::Relaxed) > max_total_memory {
+            info!(
+                "Heap target exceeds the max heap size, heap_target = {}",
+                max_total_memory
+            );
+            self.heap_target.store(max_total_memory, Ordering::Relaxed);
+        }
 
         let mut sweep_full = false;
 
@@ -280,39 +998,41 @@ impl GCTriggerPolicy<JuliaVM> for JuliaGCTrigger {
             sweep_full = true;
         }
 
-        if sweep_full {
-            if let Some(gen) = mmtk.get_plan().generational() {
-                // Force full heap in the next GC
-                gen.force_full_heap_collection();
+        // FULL_SWEEP_REASON_LARGE_PROMOTION_RATE
+        if is_nursery {
+            // many pointers in the intergen frontier => "quick" mark is not quick
+            let large_frontier = self.remset_objects.load(Ordering::Relaxed)
+                * std::mem::size_of::<usize>()
+                >= DEFAULT_COLLECT_INTERVAL;
+            let promotion_limit = std::cmp::max(
+                (self.live_after_full.load(Ordering::Relaxed) as f64 * LARGE_PROMOTION_RATE)
+                    as usize,
+                DEFAULT_COLLECT_INTERVAL,
+            );
+            let large_promotion =
+                self.promoted_since_full.load(Ordering::Relaxed) >= promotion_limit;
+            if large_frontier || large_promotion {
+                trace!(
+                    "Large promotion rate: large_frontier = {}, large_promotion = {}",
+                    large_frontier,
+                    large_promotion
+                );
+                sweep_full = true;
             }
         }
-    }
 
-    fn on_pending_allocation(&self, pages: usize) {
-        self.pending_pages.fetch_add(pages, Ordering::SeqCst);
+        sweep_full
     }
 
-    /// Is a GC required now?
-    fn is_gc_required(
-        &self,
-        space_full: bool,
-        space: Option<SpaceStats<JuliaVM>>,
-        plan: &dyn Plan<VM = JuliaVM>,
-    ) -> bool {
-        if self.is_heap_full(plan) {
-            return true;
-        }
-
-        plan.collection_required(space_full, space)
+    fn pending_allocation(&self, pages: usize) {
+        self.pending_pages.fetch_add(pages, Ordering::SeqCst);
     }
 
-    /// Is current heap full?
-    fn is_heap_full(&self, plan: &dyn Plan<VM = JuliaVM>) -> bool {
+    fn heap_full(&self, reserved_pages: usize, malloc_bytes: usize) -> bool {
         // reserved pages now should include pending allocations
-        let reserved_pages_now =
-            plan.get_reserved_pages() + self.pending_pages.load(Ordering::SeqCst);
+        let reserved_pages_now = reserved_pages + self.pending_pages.load(Ordering::SeqCst);
 
-        let heap_size = conversions::pages_to_bytes(reserved_pages_now);
+        let heap_size = conversions::pages_to_bytes(reserved_pages_now) + malloc_bytes;
 
         trace!(
             "Heap size = {}, heap target = {}",
@@ -323,19 +1043,502 @@ impl GCTriggerPolicy<JuliaVM> for JuliaGCTrigger {
         heap_size >= self.heap_target.load(Ordering::Relaxed)
     }
 
-    /// Return the current heap size (in pages)
-    fn get_current_heap_size_in_pages(&self) -> usize {
-        self.heap_target.load(Ordering::Relaxed) / BYTES_IN_PAGE
+    fn heap_target(&self) -> usize {
+        self.heap_target.load(Ordering::Relaxed)
     }
 
-    /// Return the upper bound of heap size
-    fn get_max_heap_size_in_pages(&self) -> usize {
-        self.max_total_memory.load(Ordering::Relaxed) / BYTES_IN_PAGE
+    fn max_heap_size(&self) -> usize {
+        self.max_total_memory.load(Ordering::Relaxed)
     }
 
-    /// Can the heap size grow?
     fn can_heap_size_grow(&self) -> bool {
-        true
+        // As in the stock GC, the heap can always grow unless the runtime set a hard limit.
+        !self.hard_limit_set.load(Ordering::Relaxed)
+            || self.heap_target.load(Ordering::Relaxed)
+                < self.max_total_memory.load(Ordering::Relaxed)
+    }
+
+    fn is_thrashing(&self) -> bool {
+        self.thrashing.load(Ordering::Relaxed) != 0
+    }
+
+    fn gc_overhead_limit_exceeded(&self) -> bool {
+        self.overhead_limit_exceeded.load(Ordering::Relaxed)
+    }
+}
+
+/// The allocation between two GCs, smoothed in the same way as JuliaGCTrigger does. This is shared by the policies
+/// that size the heap from the allocation rate.
+struct SmoothedAllocation {
+    /// Smoothed allocation (bytes) and mutator time (ns) between two GCs
+    bytes: AtomicUsize,
+    time: AtomicUsize,
+}
+
+impl SmoothedAllocation {
+    const SMOOTH_FACTOR: f64 = 0.95;
+
+    fn new() -> Self {
+        Self {
+            bytes: AtomicUsize::new(0),
+            time: AtomicUsize::new(0),
+        }
+    }
+
+    /// Called at the start of a GC. The heap sizes are in bytes, and the times in ns. `last_gc_end_time` is 0 if
+    /// there was no GC before, in which case we do not have a mutator time yet.
+    fn gc_start(
+        &self,
+        heap_size: usize,
+        heap_size_after_last_gc: usize,
+        now: usize,
+        last_gc_end_time: usize,
+    ) {
+        if last_gc_end_time == 0 {
+            return;
+        }
+        self.bytes.store(
+            mmtk_jl_gc_smooth(
+                self.bytes.load(Ordering::Relaxed),
+                heap_size.saturating_sub(heap_size_after_last_gc),
+                Self::SMOOTH_FACTOR,
+            ),
+            Ordering::Relaxed,
+        );
+        self.time.store(
+            mmtk_jl_gc_smooth(
+                self.time.load(Ordering::Relaxed),
+                now - last_gc_end_time,
+                Self::SMOOTH_FACTOR,
+            ),
+            Ordering::Relaxed,
+        );
+    }
+
+    fn bytes(&self) -> usize {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    fn time(&self) -> usize {
+        self.time.load(Ordering::Relaxed)
+    }
+
+    /// The allocation rate in bytes per ns, or None if we have not measured it yet.
+    fn rate(&self) -> Option<f64> {
+        let (bytes, time) = (self.bytes(), self.time());
+        if bytes != 0 && time != 0 {
+            Some(bytes as f64 / time as f64)
+        } else {
+            None
+        }
+    }
+}
+
+/// This implements the MemBalancer heap sizing rule (Kirisame et al., "Optimal heap limits for reducing
+/// browser memory use", OOPSLA 2022). After each GC, the heap limit is set to
+///
+///     heap_limit = L + sqrt(L * g / (c * s))
+///
+/// where L is the live heap size after the GC, g is the allocation rate (bytes allocated per unit of mutator time),
+/// s is the collection speed (live bytes per unit of GC time), and c is the memory/time tradeoff constant.
+/// We smooth g and s in the same way as JuliaGCTrigger does. Unlike JuliaGCTrigger, there is no extra tuning
+/// factor, and no guardrails except the minimum and maximum heap size.
+///
+/// The tradeoff constant is set in MB^-1 with the `membalancer_tradeoff` option. A larger value means a smaller heap
+/// and more time spent in GC, and a smaller value means a larger heap and less time spent in GC.
+pub struct MemBalancerTrigger {
+    min_heap_size: usize,
+    /// The max heap size given when the trigger was created. max_heap_size may differ due to the heap limits set
+    /// at runtime or the cgroup limit.
+    configured_max_heap_size: usize,
+    max_heap_size: AtomicUsize,
+    heap_target: AtomicUsize,
+    allocation: SmoothedAllocation,
+    /// Smoothed live bytes and pause time (ns) of GCs
+    gc_bytes: AtomicUsize,
+    gc_time: AtomicUsize,
+    gc_start_time: AtomicUsize,
+    gc_end_time: AtomicUsize,
+    heap_size_after_gc: AtomicUsize,
+    heap_size_before_gc: AtomicUsize,
+    pending_pages: AtomicUsize,
+}
+
+impl MemBalancerTrigger {
+    pub fn new(min_heap_size: usize, max_heap_size: usize) -> Self {
+        Self {
+            min_heap_size,
+            configured_max_heap_size: max_heap_size,
+            max_heap_size: AtomicUsize::new(max_heap_size),
+            heap_target: AtomicUsize::new(min_heap_size),
+            allocation: SmoothedAllocation::new(),
+            gc_bytes: AtomicUsize::new(0),
+            gc_time: AtomicUsize::new(0),
+            gc_start_time: AtomicUsize::new(0),
+            gc_end_time: AtomicUsize::new(0),
+            heap_size_after_gc: AtomicUsize::new(0),
+            heap_size_before_gc: AtomicUsize::new(0),
+            pending_pages: AtomicUsize::new(0),
+        }
+    }
+
+    fn heap_size(&self, reserved_pages: usize, malloc_bytes: usize) -> usize {
+        conversions::pages_to_bytes(reserved_pages + self.pending_pages.load(Ordering::SeqCst))
+            + malloc_bytes
+    }
+}
+
+impl ReplayableTrigger for MemBalancerTrigger {
+    fn gc_start(&self, inputs: &TriggerInputs) {
+        let now = inputs.time as usize;
+        self.gc_start_time.store(now, Ordering::Relaxed);
+        let heap_size = self.heap_size(inputs.reserved_pages, inputs.malloc_bytes);
+        self.heap_size_before_gc.store(heap_size, Ordering::Relaxed);
+        self.allocation.gc_start(
+            heap_size,
+            self.heap_size_after_gc.load(Ordering::Relaxed),
+            now,
+            self.gc_end_time.load(Ordering::Relaxed),
+        );
+    }
+
+    fn gc_end(&self, inputs: &TriggerInputs) -> bool {
+        const COLLECT_SMOOTH_FACTOR: f64 = 0.5;
+
+        let now = inputs.time as usize;
+        let pause = now - self.gc_start_time.load(Ordering::Relaxed);
+        self.gc_end_time.store(now, Ordering::Relaxed);
+        self.pending_pages.store(0, Ordering::SeqCst);
+
+        let live = self.heap_size(inputs.reserved_pages, inputs.malloc_bytes);
+        self.heap_size_after_gc.store(live, Ordering::Relaxed);
+
+        self.gc_bytes.store(
+            mmtk_jl_gc_smooth(
+                self.gc_bytes.load(Ordering::Relaxed),
+                live,
+                COLLECT_SMOOTH_FACTOR,
+            ),
+            Ordering::Relaxed,
+        );
+        self.gc_time.store(
+            mmtk_jl_gc_smooth(
+                self.gc_time.load(Ordering::Relaxed),
+                pause,
+                COLLECT_SMOOTH_FACTOR,
+            ),
+            Ordering::Relaxed,
+        );
+
+        let alloc_bytes = self.allocation.bytes();
+        let alloc_time = self.allocation.time();
+        let gc_bytes = self.gc_bytes.load(Ordering::Relaxed);
+        let gc_time = self.gc_time.load(Ordering::Relaxed);
+
+        let tradeoff = membalancer_tradeoff();
+        let extra = match self.allocation.rate() {
+            Some(alloc_rate) if gc_bytes != 0 && gc_time != 0 => {
+                let gc_speed = gc_bytes as f64 / gc_time as f64;
+                // The tradeoff is given per MB
+                let c = tradeoff / (1024 * 1024) as f64;
+                (live as f64 * alloc_rate / (c * gc_speed)).sqrt() as usize
+            }
+            // Before we have any measurement, allow allocating the minimal heap size.
+            _ => self.min_heap_size,
+        };
+
+        let max_heap_size =
+            inputs.update_max_heap_size(self.configured_max_heap_size, &self.max_heap_size);
+        // The soft limit bounds the target, but we still allow some allocation if the live heap exceeds it.
+        let limit = match inputs.soft_limit(max_heap_size) {
+            Some(soft_limit) => std::cmp::max(soft_limit, live + DEFAULT_COLLECT_INTERVAL / 8),
+            None => max_heap_size,
+        };
+
+        let target = std::cmp::min(std::cmp::max(live + extra, self.min_heap_size), limit);
+        info!(
+            "MemBalancer: live = {}, alloc_rate = {}/{}, gc_speed = {}/{}, tradeoff = {}, extra = {}, heap_target = {}",
+            live, alloc_bytes, alloc_time, gc_bytes, gc_time, tradeoff, extra, target
+        );
+        self.heap_target.store(target, Ordering::Relaxed);
+        // MemBalancer does not decide on full heap GCs
+        false
+    }
+
+    fn pending_allocation(&self, pages: usize) {
+        self.pending_pages.fetch_add(pages, Ordering::SeqCst);
+    }
+
+    fn heap_full(&self, reserved_pages: usize, malloc_bytes: usize) -> bool {
+        self.heap_size(reserved_pages, malloc_bytes) >= self.heap_target.load(Ordering::Relaxed)
+    }
+
+    fn heap_target(&self) -> usize {
+        self.heap_target.load(Ordering::Relaxed)
+    }
+
+    fn max_heap_size(&self) -> usize {
+        self.max_heap_size.load(Ordering::Relaxed)
+    }
+
+    fn can_heap_size_grow(&self) -> bool {
+        self.heap_target.load(Ordering::Relaxed) < self.max_heap_size.load(Ordering::Relaxed)
+    }
+}
+
+/// This keeps the GC pauses under a target (`pause_target`), while bounding the fraction of CPU time spent in GC
+/// (`gc_cpu_fraction`). We measure the pauses between gc_start and gc_end, and keep separate estimates for nursery
+/// and full heap GCs.
+///
+/// * For the CPU fraction, the mutator needs to run for pause * (1 - f) / f between two GCs. With the measured
+///   allocation rate, this gives the bytes that we allow to allocate before the next GC.
+/// * For a generational plan, the nursery pause grows with the allocation between two GCs. If nursery pauses exceed
+///   the target, we scale down the allocation budget, even if the CPU fraction is exceeded.
+/// * If full heap pauses exceed the target, we avoid full heap GCs until the heap approaches the soft limit (or the
+///   max heap size). Otherwise, we do a full heap GC when the promoted bytes are large, as JuliaGCTrigger does.
+///
+/// The heap target is bounded by the soft limit and the max heap size, so the pauses are kept under the target
+/// for as long as memory allows.
+pub struct PauseTargetTrigger {
+    min_heap_size: usize,
+    /// The max heap size given when the trigger was created. max_heap_size may differ due to the heap limits set
+    /// at runtime or the cgroup limit.
+    configured_max_heap_size: usize,
+    max_heap_size: AtomicUsize,
+    heap_target: AtomicUsize,
+    allocation: SmoothedAllocation,
+    /// Smoothed pause time (ns) of nursery and full heap GCs. 0 if we have not seen such a GC.
+    nursery_pause: AtomicUsize,
+    full_pause: AtomicUsize,
+    /// The bytes we allowed to allocate after the last GC
+    alloc_budget: AtomicUsize,
+    gc_start_time: AtomicUsize,
+    gc_end_time: AtomicUsize,
+    heap_size_after_gc: AtomicUsize,
+    /// The MMTk heap size after the last GC, without the malloc'd memory (in bytes)
+    mmtk_heap_size_after_gc: AtomicUsize,
+    /// The MMTk heap size after the last full heap GC, and the bytes promoted since then (in bytes)
+    live_after_full: AtomicUsize,
+    promoted_since_full: AtomicUsize,
+    pending_pages: AtomicUsize,
+}
+
+impl PauseTargetTrigger {
+    pub fn new(min_heap_size: usize, max_heap_size: usize) -> Self {
+        Self {
+            min_heap_size,
+            configured_max_heap_size: max_heap_size,
+            max_heap_size: AtomicUsize::new(max_heap_size),
+            heap_target: AtomicUsize::new(min_heap_size),
+            allocation: SmoothedAllocation::new(),
+            nursery_pause: AtomicUsize::new(0),
+            full_pause: AtomicUsize::new(0),
+            alloc_budget: AtomicUsize::new(min_heap_size),
+            gc_start_time: AtomicUsize::new(0),
+            gc_end_time: AtomicUsize::new(0),
+            heap_size_after_gc: AtomicUsize::new(0),
+            mmtk_heap_size_after_gc: AtomicUsize::new(0),
+            live_after_full: AtomicUsize::new(0),
+            promoted_since_full: AtomicUsize::new(0),
+            pending_pages: AtomicUsize::new(0),
+        }
+    }
+
+    fn heap_size(&self, reserved_pages: usize, malloc_bytes: usize) -> usize {
+        conversions::pages_to_bytes(reserved_pages + self.pending_pages.load(Ordering::SeqCst))
+            + malloc_bytes
+    }
+
+    fn smooth_pause(pause: &AtomicUsize, new_pause: usize) {
+        const PAUSE_SMOOTH_FACTOR: f64 = 0.5;
+        let old = pause.load(Ordering::Relaxed);
+        pause.store(
+            if old == 0 {
+                new_pause
+            } else {
+                mmtk_jl_gc_smooth(old, new_pause, PAUSE_SMOOTH_FACTOR)
+            },
+            Ordering::Relaxed,
+        );
+    }
+}
+
+impl ReplayableTrigger for PauseTargetTrigger {
+    fn gc_start(&self, inputs: &TriggerInputs) {
+        let now = inputs.time as usize;
+        self.gc_start_time.store(now, Ordering::Relaxed);
+        self.allocation.gc_start(
+            self.heap_size(inputs.reserved_pages, inputs.malloc_bytes),
+            self.heap_size_after_gc.load(Ordering::Relaxed),
+            now,
+            self.gc_end_time.load(Ordering::Relaxed),
+        );
+    }
+
+    fn gc_end(&self, inputs: &TriggerInputs) -> bool {
+        let now = inputs.time as usize;
+        let pause = now - self.gc_start_time.load(Ordering::Relaxed);
+        self.gc_end_time.store(now, Ordering::Relaxed);
+        self.pending_pages.store(0, Ordering::SeqCst);
+
+        let live = self.heap_size(inputs.reserved_pages, inputs.malloc_bytes);
+        // Only objects in the MMTk heap are promoted, so we leave out the malloc'd memory.
+        let mmtk_live = conversions::pages_to_bytes(inputs.reserved_pages);
+        let is_nursery = inputs.full_heap == Some(false);
+        if is_nursery {
+            Self::smooth_pause(&self.nursery_pause, pause);
+            let promoted =
+                mmtk_live.saturating_sub(self.mmtk_heap_size_after_gc.load(Ordering::Relaxed));
+            self.promoted_since_full
+                .fetch_add(promoted, Ordering::Relaxed);
+        } else {
+            Self::smooth_pause(&self.full_pause, pause);
+            self.live_after_full.store(mmtk_live, Ordering::Relaxed);
+            self.promoted_since_full.store(0, Ordering::Relaxed);
+        }
+        self.heap_size_after_gc.store(live, Ordering::Relaxed);
+        self.mmtk_heap_size_after_gc
+            .store(mmtk_live, Ordering::Relaxed);
+
+        let max_heap_size =
+            inputs.update_max_heap_size(self.configured_max_heap_size, &self.max_heap_size);
+        let limit = inputs.soft_limit(max_heap_size).unwrap_or(max_heap_size);
+
+        let pause_target = (pause_target() * 1e6) as usize;
+        let cpu_fraction = gc_cpu_fraction();
+        // The pause we expect for the next GC. For a generational plan, this is usually a nursery GC.
+        let generational = inputs.full_heap.is_some();
+        let expected_pause = if generational && self.nursery_pause.load(Ordering::Relaxed) != 0 {
+            self.nursery_pause.load(Ordering::Relaxed)
+        } else {
+            pause
+        };
+
+        // The budget to keep the GC CPU fraction
+        let mut budget = if let Some(alloc_rate) = self.allocation.rate() {
+            (alloc_rate * expected_pause as f64 * (1.0 - cpu_fraction) / cpu_fraction) as usize
+        } else {
+            // Before we have any measurement, allow allocating the minimal heap size.
+            self.min_heap_size
+        };
+        // The nursery pause grows with the allocation, so scale the budget down if the nursery pauses are too long.
+        let nursery_pause = self.nursery_pause.load(Ordering::Relaxed);
+        if generational && nursery_pause > pause_target {
+            let last_budget = self.alloc_budget.load(Ordering::Relaxed);
+            let scaled = (last_budget as f64 * pause_target as f64 / nursery_pause as f64) as usize;
+            budget = std::cmp::min(budget, scaled);
+        }
+        let budget = std::cmp::max(budget, DEFAULT_COLLECT_INTERVAL / 8);
+        self.alloc_budget.store(budget, Ordering::Relaxed);
+
+        // The soft limit bounds the target, but we still allow some allocation if the live heap exceeds it.
+        // The hard limit always bounds the target.
+        let target = std::cmp::min(
+            std::cmp::max(live + budget, self.min_heap_size),
+            std::cmp::max(limit, live + DEFAULT_COLLECT_INTERVAL / 8),
+        );
+        let target = std::cmp::min(target, max_heap_size);
+        self.heap_target.store(target, Ordering::Relaxed);
+
+        // Decide whether the next GC should be a full heap GC.
+        let full_pause = self.full_pause.load(Ordering::Relaxed);
+        let sweep_full = if !generational || !is_nursery {
+            false
+        } else if full_pause <= pause_target {
+            // Full heap GCs are cheap enough. Collect the promoted objects as JuliaGCTrigger does.
+            let promotion_limit = std::cmp::max(
+                (self.live_after_full.load(Ordering::Relaxed) as f64 * LARGE_PROMOTION_RATE)
+                    as usize,
+                DEFAULT_COLLECT_INTERVAL,
+            );
+            self.promoted_since_full.load(Ordering::Relaxed) >= promotion_limit
+        } else {
+            // Full heap GCs exceed the pause target. Only do one if we are running out of memory.
+            live >= limit * 9 / 10
+        };
+
+        info!(
+            "PauseTarget: live = {}, pause = {}, nursery_pause = {}, full_pause = {}, pause_target = {}, cpu_fraction = {}, budget = {}, heap_target = {}, sweep_full = {}",
+            live, pause, nursery_pause, full_pause, pause_target, cpu_fraction, budget, target, sweep_full
+        );
+        sweep_full
+    }
+
+    fn pending_allocation(&self, pages: usize) {
+        self.pending_pages.fetch_add(pages, Ordering::SeqCst);
+    }
+
+    fn heap_full(&self, reserved_pages: usize, malloc_bytes: usize) -> bool {
+        self.heap_size(reserved_pages, malloc_bytes) >= self.heap_target.load(Ordering::Relaxed)
+    }
+
+    fn heap_target(&self) -> usize {
+        self.heap_target.load(Ordering::Relaxed)
+    }
+
+    fn max_heap_size(&self) -> usize {
+        self.max_heap_size.load(Ordering::Relaxed)
+    }
+
+    fn can_heap_size_grow(&self) -> bool {
+        self.heap_target.load(Ordering::Relaxed) < self.max_heap_size.load(Ordering::Relaxed)
+    }
+}
+
+/// A fixed heap size. Unlike MMTk's FixedHeapSize trigger, the heap size follows the heap limits set at runtime:
+/// we collect when the heap reaches the soft limit if it is set, otherwise the hard limit.
+pub struct FixedHeapSizeTrigger {
+    /// The heap size given when the trigger was created
+    configured_heap_size: usize,
+    max_heap_size: AtomicUsize,
+    heap_target: AtomicUsize,
+    pending_pages: AtomicUsize,
+}
+
+impl FixedHeapSizeTrigger {
+    pub fn new(heap_size: usize) -> Self {
+        Self {
+            configured_heap_size: heap_size,
+            max_heap_size: AtomicUsize::new(heap_size),
+            heap_target: AtomicUsize::new(heap_size),
+            pending_pages: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl ReplayableTrigger for FixedHeapSizeTrigger {
+    fn gc_start(&self, _inputs: &TriggerInputs) {}
+
+    fn gc_end(&self, inputs: &TriggerInputs) -> bool {
+        self.pending_pages.store(0, Ordering::SeqCst);
+        let max_heap_size =
+            inputs.update_max_heap_size(self.configured_heap_size, &self.max_heap_size);
+        let target = inputs.soft_limit(max_heap_size).unwrap_or(max_heap_size);
+        self.heap_target.store(target, Ordering::Relaxed);
+        false
+    }
+
+    fn pending_allocation(&self, pages: usize) {
+        self.pending_pages.fetch_add(pages, Ordering::SeqCst);
+    }
+
+    fn heap_full(&self, reserved_pages: usize, malloc_bytes: usize) -> bool {
+        let heap_size =
+            conversions::pages_to_bytes(reserved_pages + self.pending_pages.load(Ordering::SeqCst))
+                + malloc_bytes;
+        heap_size > self.heap_target.load(Ordering::Relaxed)
+    }
+
+    fn heap_target(&self) -> usize {
+        self.heap_target.load(Ordering::Relaxed)
+    }
+
+    fn max_heap_size(&self) -> usize {
+        self.max_heap_size.load(Ordering::Relaxed)
+    }
+
+    fn can_heap_size_grow(&self) -> bool {
+        false
     }
 }
 
@@ -369,3 +1572,261 @@ fn mmtk_overallocation(old_val: usize, val: usize, max_val: usize) -> usize {
         inc
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MB: usize = 1024 * 1024;
+
+    fn pages(bytes: usize) -> usize {
+        bytes / BYTES_IN_PAGE
+    }
+
+    fn inputs(time: u64, heap_size: usize) -> TriggerInputs {
+        TriggerInputs {
+            time,
+            reserved_pages: pages(heap_size),
+            user_triggered: false,
+            full_heap: None,
+            remset_objects: 0,
+            malloc_bytes: 0,
+            memory_limit: usize::MAX,
+            soft_limit: usize::MAX,
+            hard_limit: usize::MAX,
+        }
+    }
+
+    #[test]
+    fn parse_gc_overhead_limit_values() {
+        assert_eq!(parse_gc_overhead_limit("off"), Ok(None));
+        assert_eq!(
+            parse_gc_overhead_limit("on"),
+            Ok(Some(GCOverheadLimit::default()))
+        );
+        assert_eq!(
+            parse_gc_overhead_limit("0.9,0.05,3"),
+            Ok(Some(GCOverheadLimit {
+                gc_time_ratio: 0.9,
+                heap_free_ratio: 0.05,
+                cycles: 3,
+            }))
+        );
+        assert_eq!(
+            parse_gc_overhead_limit("1,0,1"),
+            Ok(Some(GCOverheadLimit {
+                gc_time_ratio: 1.0,
+                heap_free_ratio: 0.0,
+                cycles: 1,
+            }))
+        );
+        for invalid in [
+            "",
+            "On",
+            "true",
+            "0.9,0.05",
+            "0.9,0.05,3,1",
+            "1.5,0.05,3",
+            "0.9,-0.1,3",
+            "0.9,0.05,0",
+            "0.9,0.05,x",
+            "NaN,0.05,3",
+        ] {
+            assert!(
+                parse_gc_overhead_limit(invalid).is_err(),
+                "{} should be rejected",
+                invalid
+            );
+        }
+    }
+
+    #[test]
+    fn stock_target_is_bounded_by_hard_limit() {
+        let trigger = JuliaGCTrigger::new(1024 * MB);
+        trigger.gc_start(&inputs(1_000_000_000, 32 * MB));
+        // The min_target_allocs guardrail alone would put the target at 40 MB + DEFAULT_COLLECT_INTERVAL / 8.
+        trigger.gc_end(&TriggerInputs {
+            hard_limit: 42 * MB,
+            ..inputs(1_050_000_000, 40 * MB)
+        });
+        assert_eq!(trigger.heap_target(), 42 * MB);
+        assert!(!trigger.can_heap_size_grow());
+
+        // A user triggered GC keeps the target, but not above a lowered limit.
+        trigger.gc_start(&inputs(2_000_000_000, 42 * MB));
+        trigger.gc_end(&TriggerInputs {
+            user_triggered: true,
+            hard_limit: 36 * MB,
+            ..inputs(2_050_000_000, 30 * MB)
+        });
+        assert_eq!(trigger.heap_target(), 36 * MB);
+        assert_eq!(trigger.max_heap_size(), 36 * MB);
+    }
+
+    #[test]
+    fn stock_heap_can_grow_without_hard_limit() {
+        let trigger = JuliaGCTrigger::new(42 * MB);
+        trigger.gc_start(&inputs(1_000_000_000, 32 * MB));
+        trigger.gc_end(&inputs(1_050_000_000, 40 * MB));
+        assert_eq!(trigger.heap_target(), 42 * MB);
+        assert!(trigger.can_heap_size_grow());
+
+        // Only a hard limit set at runtime stops the growth.
+        trigger.gc_start(&inputs(2_000_000_000, 42 * MB));
+        trigger.gc_end(&TriggerInputs {
+            hard_limit: 42 * MB,
+            ..inputs(2_050_000_000, 40 * MB)
+        });
+        assert!(!trigger.can_heap_size_grow());
+    }
+
+    #[test]
+    fn dynamic_target_is_bounded_by_min_heap_size() {
+        // julia_trigger=dynamic
+        let trigger = JuliaGCTrigger::with_min_heap_size(256 * MB, 1024 * MB);
+        assert_eq!(trigger.heap_target(), 256 * MB);
+        trigger.gc_start(&inputs(1_000_000_000, 32 * MB));
+        trigger.gc_end(&inputs(1_050_000_000, 8 * MB));
+        assert_eq!(trigger.heap_target(), 256 * MB);
+
+        // The stock heuristics would shrink the target to the live heap plus the guardrail.
+        let stock = JuliaGCTrigger::new(1024 * MB);
+        stock.gc_start(&inputs(1_000_000_000, 32 * MB));
+        stock.gc_end(&inputs(1_050_000_000, 8 * MB));
+        assert!(stock.heap_target() < 256 * MB);
+    }
+
+    #[test]
+    fn membalancer_allows_min_heap_size_before_measurements() {
+        let trigger = MemBalancerTrigger::new(16 * MB, 1024 * MB);
+        trigger.gc_start(&inputs(1_000_000, 32 * MB));
+        assert!(!trigger.gc_end(&inputs(2_000_000, 8 * MB)));
+        assert_eq!(trigger.heap_target(), 8 * MB + 16 * MB);
+    }
+
+    #[test]
+    fn membalancer_target_follows_square_root_rule() {
+        let trigger = MemBalancerTrigger::new(MB, 1024 * MB);
+        trigger.gc_start(&inputs(1_000_000, 32 * MB));
+        trigger.gc_end(&inputs(2_000_000, 8 * MB));
+        trigger.gc_start(&inputs(102_000_000, 40 * MB));
+        trigger.gc_end(&inputs(104_000_000, 8 * MB));
+
+        let alloc_bytes = mmtk_jl_gc_smooth(0, 32 * MB, 0.95);
+        let alloc_time = mmtk_jl_gc_smooth(0, 100_000_000, 0.95);
+        let gc_bytes = mmtk_jl_gc_smooth(mmtk_jl_gc_smooth(0, 8 * MB, 0.5), 8 * MB, 0.5);
+        let gc_time = mmtk_jl_gc_smooth(mmtk_jl_gc_smooth(0, 1_000_000, 0.5), 2_000_000, 0.5);
+        let alloc_rate = alloc_bytes as f64 / alloc_time as f64;
+        let gc_speed = gc_bytes as f64 / gc_time as f64;
+        let c = membalancer_tradeoff() / MB as f64;
+        let extra = ((8 * MB) as f64 * alloc_rate / (c * gc_speed)).sqrt() as usize;
+        assert_eq!(trigger.heap_target(), 8 * MB + extra);
+    }
+
+    #[test]
+    fn membalancer_target_grows_with_allocation_rate() {
+        let target = |alloc: usize| {
+            let trigger = MemBalancerTrigger::new(MB, 1024 * MB);
+            trigger.gc_start(&inputs(1_000_000, 32 * MB));
+            trigger.gc_end(&inputs(2_000_000, 8 * MB));
+            trigger.gc_start(&inputs(102_000_000, 8 * MB + alloc));
+            trigger.gc_end(&inputs(104_000_000, 8 * MB));
+            trigger.heap_target()
+        };
+        assert!(target(64 * MB) > target(16 * MB));
+    }
+
+    #[test]
+    fn membalancer_target_is_bounded_by_heap_limits() {
+        let trigger = MemBalancerTrigger::new(16 * MB, 1024 * MB);
+        trigger.gc_start(&inputs(1_000_000, 32 * MB));
+        trigger.gc_end(&TriggerInputs {
+            soft_limit: 20 * MB,
+            ..inputs(2_000_000, 8 * MB)
+        });
+        assert_eq!(trigger.heap_target(), 20 * MB);
+        assert_eq!(trigger.max_heap_size(), 1024 * MB);
+
+        trigger.gc_start(&inputs(3_000_000, 20 * MB));
+        trigger.gc_end(&TriggerInputs {
+            hard_limit: 12 * MB,
+            ..inputs(4_000_000, 8 * MB)
+        });
+        assert_eq!(trigger.heap_target(), 12 * MB);
+        assert_eq!(trigger.max_heap_size(), 12 * MB);
+        assert!(!trigger.can_heap_size_grow());
+    }
+
+    fn generational(time: u64, heap_size: usize, full_heap: bool) -> TriggerInputs {
+        TriggerInputs {
+            full_heap: Some(full_heap),
+            ..inputs(time, heap_size)
+        }
+    }
+
+    #[test]
+    fn pause_target_budget_keeps_cpu_fraction() {
+        let trigger = PauseTargetTrigger::new(16 * MB, 1024 * MB);
+        trigger.gc_start(&inputs(1_000_000_000, 32 * MB));
+        trigger.gc_end(&inputs(1_100_000_000, 8 * MB));
+        // Before we measure the allocation rate, we allow allocating the min heap size.
+        assert_eq!(trigger.heap_target(), 8 * MB + 16 * MB);
+
+        trigger.gc_start(&inputs(2_100_000_000, 40 * MB));
+        trigger.gc_end(&inputs(2_200_000_000, 8 * MB));
+        let alloc_rate = mmtk_jl_gc_smooth(0, 32 * MB, 0.95) as f64
+            / mmtk_jl_gc_smooth(0, 1_000_000_000, 0.95) as f64;
+        let cpu_fraction = gc_cpu_fraction();
+        let budget = (alloc_rate * 100_000_000_f64 * (1.0 - cpu_fraction) / cpu_fraction) as usize;
+        assert!(budget > DEFAULT_COLLECT_INTERVAL / 8);
+        assert_eq!(trigger.heap_target(), 8 * MB + budget);
+    }
+
+    #[test]
+    fn pause_target_avoids_full_heap_gcs_over_target() {
+        let trigger = PauseTargetTrigger::new(16 * MB, 1024 * MB);
+        // A full heap GC of 100 ms, and then a nursery GC of 50 ms. Both exceed the default target of 10 ms.
+        trigger.gc_start(&generational(1_000_000_000, 32 * MB, true));
+        trigger.gc_end(&generational(1_100_000_000, 8 * MB, true));
+        trigger.gc_start(&generational(2_100_000_000, 40 * MB, true));
+        assert!(!trigger.gc_end(&generational(2_150_000_000, 16 * MB, false)));
+        // The budget is scaled down by the nursery pause (to 16 MB * 10 / 50), and bounded below.
+        assert_eq!(
+            trigger.heap_target(),
+            16 * MB + DEFAULT_COLLECT_INTERVAL / 8
+        );
+
+        // Close to the limit, we do a full heap GC anyway.
+        trigger.gc_start(&generational(3_000_000_000, 24 * MB, false));
+        assert!(trigger.gc_end(&TriggerInputs {
+            hard_limit: 21 * MB,
+            ..generational(3_050_000_000, 20 * MB, false)
+        }));
+        assert_eq!(trigger.heap_target(), 21 * MB);
+    }
+
+    #[test]
+    fn pause_target_promoted_bytes_exclude_malloc() {
+        let trigger = PauseTargetTrigger::new(16 * MB, 1024 * MB);
+        // Full heap GCs of 1 ms are well under the target, so we collect promoted objects.
+        trigger.gc_start(&generational(1_000_000_000, 32 * MB, true));
+        trigger.gc_end(&generational(1_001_000_000, 8 * MB, true));
+
+        // The malloc'd memory grows by 64 MB, but nothing is promoted.
+        trigger.gc_start(&generational(2_000_000_000, 16 * MB, true));
+        assert!(!trigger.gc_end(&TriggerInputs {
+            malloc_bytes: 64 * MB,
+            ..generational(2_001_000_000, 8 * MB, false)
+        }));
+
+        // 48 MB are promoted, which exceeds DEFAULT_COLLECT_INTERVAL.
+        trigger.gc_start(&TriggerInputs {
+            malloc_bytes: 64 * MB,
+            ..generational(3_000_000_000, 64 * MB, false)
+        });
+        assert!(trigger.gc_end(&TriggerInputs {
+            malloc_bytes: 64 * MB,
+            ..generational(3_001_000_000, 56 * MB, false)
+        }));
+    }
+}
@@ -14,12 +14,15 @@ use crate::jl_gc_get_thread_finalizer_list;
 use crate::jl_gc_get_to_finalize_list;
 
 /// This is a Rust implementation of finalizer scanning in _jl_gc_collect() in gc.c
-pub fn scan_finalizers_in_rust<T: ObjectTracer>(tracer: &mut T) {
+/// Return true if we found objects that are ready for finalization.
+pub fn scan_finalizers_in_rust<T: ObjectTracer>(tracer: &mut T) -> bool {
     use crate::mmtk::vm::ActivePlan;
     let to_finalize = ArrayListT::to_finalize_list();
     let marked_finalizers_list = ArrayListT::marked_finalizers_list();
     let jl_gc_have_pending_finalizers: *mut i32 = unsafe { jl_gc_get_have_pending_finalizers() };
 
+    let orig_to_finalize_len = to_finalize.len;
+
     // Current length of marked list: we only need to trace objects after this length if this is a nursery GC.
     let mut orig_marked_len = marked_finalizers_list.len;
 
@@ -54,6 +57,8 @@ pub fn scan_finalizers_in_rust<T: ObjectTracer>(tracer: &mut T) {
     mark_finlist(marked_finalizers_list, orig_marked_len, tracer);
     // Trace objects in to_finalize (which are just pushed in sweeping thread local list)
     mark_finlist(to_finalize, 0, tracer);
+
+    to_finalize.len > orig_to_finalize_len
 }
 
 /// This maps to arraylist_t in arraylist.h. Defining the type allows us to access the list in Rust.
@@ -12,20 +12,33 @@ use mmtk::vm::VMBinding;
 use mmtk::MMTKBuilder;
 use mmtk::MMTK;
 
-use std::collections::HashMap;
+use crate::mutator_registry::MutatorRegistry;
 use std::sync::atomic::AtomicIsize;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::{Arc, Condvar, Mutex, RwLock};
 
 pub mod active_plan;
+pub mod affinity;
+pub mod alloc_stats;
 pub mod api;
 mod build_info;
+pub mod cgroup;
 pub mod collection;
+pub mod config;
+pub mod finalization;
+pub mod gc_stats;
+pub mod gc_trace;
 pub mod gc_trigger;
+pub mod memory_pressure;
+pub mod mutator_registry;
 pub mod object_model;
+pub mod oom;
 pub mod reference_glue;
+pub mod safepoint;
 pub mod scanning;
 pub mod slots;
+pub mod space_stats;
+pub mod trigger_trace;
 pub mod util;
 
 pub mod julia_finalizer;
@@ -90,11 +103,8 @@ lazy_static! {
     pub static ref STOP_MUTATORS: Arc<(Mutex<usize>, Condvar)> =
         Arc::new((Mutex::new(0), Condvar::new()));
 
-    // We create a boxed mutator with MMTk core, and we mem copy its content to jl_tls_state_t (shallow copy).
-    // This map stores the pair of the mutator address in jl_tls_state_t and the original boxed mutator.
-    // As we only do a shallow copy, we should not free the original boxed mutator, until the thread is getting destroyed.
-    // Otherwise, we will have dangling pointers.
-    pub static ref MUTATORS: RwLock<HashMap<Address, Address>> = RwLock::new(HashMap::new());
+    // The bound mutators. See mutator_registry.rs
+    pub static ref MUTATORS: RwLock<MutatorRegistry> = RwLock::new(MutatorRegistry::default());
 }
 
 type ProcessSlotFn = *const extern "C" fn(closure: Address, slot: Address);
@@ -121,6 +131,11 @@ extern "C" {
     pub fn jl_gc_get_owner_address_to_mmtk(m: Address) -> Address;
     pub fn jl_gc_genericmemory_how(m: Address) -> usize;
     pub fn jl_gc_get_max_memory() -> usize;
+    pub fn jl_adopt_thread() -> Address;
+    pub fn jl_get_ptls_states() -> *mut crate::julia_types::_jl_tls_states_t;
+    pub fn jl_get_current_task() -> Address;
+    pub fn jl_gc_run_pending_finalizers(ct: Address);
+    pub fn jl_gc_safepoint();
     pub static mut MMTK_SIDE_LOG_BIT_BASE_ADDRESS: Address;
 }
 
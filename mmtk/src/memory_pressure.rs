@@ -0,0 +1,143 @@
+// Notify the runtime about memory pressure, so applications can drop caches before the heap runs out.
+//
+// A callback is registered with `mmtk_register_memory_pressure_callback` with a list of fractions of the max heap size.
+// At the end of a GC, the trigger policy reports the heap size after the GC. If the heap usage crosses a higher
+// fraction than it did after the previous GC, or thrashing is newly detected, a notification is queued.
+// The notification is delivered on the mutator that triggered the GC after the mutators are resumed
+// (in `block_for_gc`), never on a GC worker.
+
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+use log::*;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, RwLock};
+
+/// The callback for memory pressure. The arguments are the heap size after the GC, the max heap size (both in bytes),
+/// the highest fraction of the max heap size that is crossed (0 if none), and whether the GC is thrashing.
+/// The callback runs in the GC slow path of a mutator. It should not allocate in the Julia heap or trigger a GC,
+/// but only record the pressure (e.g. set a flag or notify a task) and return.
+pub type MemoryPressureCallback =
+    extern "C" fn(heap_size: usize, max_heap_size: usize, threshold: f64, thrashing: bool);
+
+/// The fractions of the max heap size that we notify on if the caller does not provide any.
+pub const DEFAULT_THRESHOLDS: [f64; 3] = [0.7, 0.85, 0.95];
+
+struct Registration {
+    callback: MemoryPressureCallback,
+    /// Sorted fractions of the max heap size
+    thresholds: Vec<f64>,
+}
+
+/// A notification that is waiting to be delivered to the mutator
+#[derive(Clone, Copy, Debug)]
+struct MemoryPressure {
+    heap_size: usize,
+    max_heap_size: usize,
+    threshold: f64,
+    thrashing: bool,
+}
+
+lazy_static! {
+    static ref REGISTRATION: RwLock<Option<Registration>> = RwLock::new(None);
+    static ref PENDING: Mutex<Option<MemoryPressure>> = Mutex::new(None);
+}
+
+/// The number of thresholds that the heap usage crossed after the last GC
+static LEVEL: AtomicUsize = AtomicUsize::new(0);
+/// Was the GC thrashing after the last GC?
+static THRASHING: AtomicBool = AtomicBool::new(false);
+
+/// Called by the trigger policy at the end of each GC. Queue a notification if the pressure increased.
+pub(crate) fn on_gc_end(heap_size: usize, max_heap_size: usize, thrashing: bool) {
+    let registration = REGISTRATION.read().unwrap();
+    let registration = match registration.as_ref() {
+        Some(registration) => registration,
+        None => return,
+    };
+
+    let usage = heap_size as f64 / max_heap_size as f64;
+    let level = registration
+        .thresholds
+        .iter()
+        .take_while(|threshold| usage >= **threshold)
+        .count();
+    let old_level = LEVEL.swap(level, Ordering::SeqCst);
+    let old_thrashing = THRASHING.swap(thrashing, Ordering::SeqCst);
+
+    if level > old_level || (thrashing && !old_thrashing) {
+        let pressure = MemoryPressure {
+            heap_size,
+            max_heap_size,
+            threshold: if level == 0 {
+                0f64
+            } else {
+                registration.thresholds[level - 1]
+            },
+            thrashing,
+        };
+        info!("Memory pressure: {:?}", pressure);
+        // If the previous notification is not delivered yet, this one supersedes it.
+        *PENDING.lock().unwrap() = Some(pressure);
+    }
+}
+
+/// Deliver the queued notification, if any. This is called on a mutator after the GC.
+pub(crate) fn deliver_pending() {
+    let pressure = match PENDING.lock().unwrap().take() {
+        Some(pressure) => pressure,
+        None => return,
+    };
+    // Do not hold the lock while the callback runs: the callback may register a new callback.
+    let callback = match REGISTRATION.read().unwrap().as_ref() {
+        Some(registration) => registration.callback,
+        None => return,
+    };
+    trace!("Delivering memory pressure: {:?}", pressure);
+    callback(
+        pressure.heap_size,
+        pressure.max_heap_size,
+        pressure.threshold,
+        pressure.thrashing,
+    );
+}
+
+/// Register a callback for memory pressure, with `n_thresholds` fractions of the max heap size in `thresholds`.
+/// If `n_thresholds` is 0, DEFAULT_THRESHOLDS are used. A NULL callback unregisters the current callback.
+/// Return false if any threshold is not in (0, 1].
+#[no_mangle]
+pub extern "C" fn mmtk_register_memory_pressure_callback(
+    callback: Option<MemoryPressureCallback>,
+    thresholds: *const f64,
+    n_thresholds: usize,
+) -> bool {
+    let callback = match callback {
+        Some(callback) => callback,
+        None => {
+            *REGISTRATION.write().unwrap() = None;
+            *PENDING.lock().unwrap() = None;
+            return true;
+        }
+    };
+
+    let mut thresholds = if n_thresholds == 0 || thresholds.is_null() {
+        DEFAULT_THRESHOLDS.to_vec()
+    } else {
+        unsafe { std::slice::from_raw_parts(thresholds, n_thresholds) }.to_vec()
+    };
+    if let Some(invalid) = thresholds.iter().find(|t| !(**t > 0f64 && **t <= 1f64)) {
+        warn!("Invalid memory pressure threshold: {}", invalid);
+        return false;
+    }
+    thresholds.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+    info!(
+        "Registered memory pressure callback with thresholds {:?}",
+        thresholds
+    );
+    *REGISTRATION.write().unwrap() = Some(Registration {
+        callback,
+        thresholds,
+    });
+    LEVEL.store(0, Ordering::SeqCst);
+    THRASHING.store(false, Ordering::SeqCst);
+    true
+}
@@ -0,0 +1,130 @@
+// The registry of the bound mutators.
+//
+// We create a boxed mutator with MMTk core, and we mem copy its content to jl_tls_state_t (shallow copy).
+// The registry stores the mutator address in jl_tls_state_t with the original boxed mutator. As we only do a shallow
+// copy, we should not free the original boxed mutator, until the thread is getting destroyed. Otherwise, we will have
+// dangling pointers.
+//
+// A mutator is registered in mmtk_post_bind_mutator (once the mutator is copied to jl_tls_state_t), and removed in
+// mmtk_destroy_mutator. Besides the mutator address, the registry is indexed by the mutator TLS (jl_tls_state_t)
+// and by the Julia thread id, so looking up a mutator is O(1).
+
+use crate::alloc_stats::AllocCounters;
+use crate::julia_types::_jl_tls_states_t;
+use crate::JuliaVM;
+use mmtk::util::Address;
+use mmtk::Mutator;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// A registered mutator
+#[derive(Clone)]
+pub struct MutatorEntry {
+    /// The mutator in jl_tls_state_t
+    pub mutator: Address,
+    /// The original boxed mutator that is created by MMTk core
+    pub boxed_mutator: Address,
+    /// The mutator TLS, i.e. the jl_tls_state_t of the thread
+    pub tls: Address,
+    /// The Julia thread id
+    pub tid: i16,
+    /// When the mutator was bound (jl_hrtime)
+    pub bind_time: u64,
+    /// The allocation counters. See alloc_stats.rs
+    pub alloc_counters: Arc<AllocCounters>,
+}
+
+impl MutatorEntry {
+    /// Create an entry for the mutator in jl_tls_state_t and its original boxed mutator.
+    ///
+    /// # Safety
+    /// The mutator needs to be a valid mutator that is bound to a Julia thread.
+    pub unsafe fn new(
+        mutator: *mut Mutator<JuliaVM>,
+        boxed_mutator: *mut Mutator<JuliaVM>,
+    ) -> Self {
+        let tls = (*mutator).mutator_tls.0 .0.to_address();
+        let tid = (*tls.to_ptr::<_jl_tls_states_t>()).tid;
+        Self {
+            mutator: Address::from_mut_ptr(mutator),
+            boxed_mutator: Address::from_mut_ptr(boxed_mutator),
+            tls,
+            tid,
+            bind_time: crate::jl_hrtime(),
+            alloc_counters: Arc::new(AllocCounters::default()),
+        }
+    }
+
+    /// The mutator in jl_tls_state_t
+    ///
+    /// # Safety
+    /// The caller needs to make sure that the mutator is not destroyed, and not accessed by others mutably.
+    pub unsafe fn mutator(&self) -> &'static mut Mutator<JuliaVM> {
+        &mut *self.mutator.to_mut_ptr::<Mutator<JuliaVM>>()
+    }
+}
+
+#[derive(Default)]
+pub struct MutatorRegistry {
+    /// The entries by the mutator address
+    entries: HashMap<Address, MutatorEntry>,
+    /// The mutator address by the mutator TLS
+    by_tls: HashMap<Address, Address>,
+    /// The mutator address by the Julia thread id
+    by_tid: HashMap<i16, Address>,
+}
+
+impl MutatorRegistry {
+    pub fn insert(&mut self, entry: MutatorEntry) {
+        self.by_tls.insert(entry.tls, entry.mutator);
+        self.by_tid.insert(entry.tid, entry.mutator);
+        self.entries.insert(entry.mutator, entry);
+    }
+
+    /// Remove the entry for the mutator (in jl_tls_state_t).
+    pub fn remove(&mut self, mutator: Address) -> Option<MutatorEntry> {
+        let entry = self.entries.remove(&mutator)?;
+        self.by_tls.remove(&entry.tls);
+        self.by_tid.remove(&entry.tid);
+        Some(entry)
+    }
+
+    /// Remove all the entries.
+    pub fn drain(&mut self) -> impl Iterator<Item = MutatorEntry> + '_ {
+        self.by_tls.clear();
+        self.by_tid.clear();
+        self.entries.drain().map(|(_, entry)| entry)
+    }
+
+    pub fn get(&self, mutator: Address) -> Option<&MutatorEntry> {
+        self.entries.get(&mutator)
+    }
+
+    pub fn get_by_tls(&self, tls: Address) -> Option<&MutatorEntry> {
+        self.by_tls
+            .get(&tls)
+            .and_then(|mutator| self.entries.get(mutator))
+    }
+
+    pub fn get_by_tid(&self, tid: i16) -> Option<&MutatorEntry> {
+        self.by_tid
+            .get(&tid)
+            .and_then(|mutator| self.entries.get(mutator))
+    }
+
+    pub fn contains_tls(&self, tls: Address) -> bool {
+        self.by_tls.contains_key(&tls)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &MutatorEntry> {
+        self.entries.values()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
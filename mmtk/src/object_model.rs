@@ -5,12 +5,13 @@ use crate::julia_scanning::{
     jl_genericmemory_typename, jl_small_typeof, mmtk_jl_typeof, mmtk_jl_typetagof,
 };
 use crate::julia_types::*;
-use crate::{JuliaVM, JULIA_BUFF_TAG, JULIA_HEADER_SIZE};
+use crate::{JuliaVM, JULIA_BUFF_TAG, JULIA_HEADER_SIZE, SINGLETON};
 use log::trace;
 use mmtk::util::copy::*;
 use mmtk::util::{Address, ObjectReference};
 use mmtk::vm::ObjectModel;
 use mmtk::vm::*;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 pub struct VMObjectModel {}
 
@@ -168,11 +169,50 @@ impl ObjectModel<JuliaVM> for VMObjectModel {
     }
 }
 
+/// The address range of the large object space. The range depends on the plan, and is set in mmtk_gc_init.
+static LOS_START: AtomicUsize = AtomicUsize::new(0);
+static LOS_END: AtomicUsize = AtomicUsize::new(0);
+
+/// Find the address range of the large object space of the plan. This is called once MMTk is initialized.
+pub(crate) fn init_los_range() {
+    use mmtk::plan::HasSpaces;
+    use mmtk::policy::space::Space;
+
+    SINGLETON
+        .get_plan()
+        .for_each_space(&mut |space: &dyn Space<JuliaVM>| {
+            if space.get_name() == "los" {
+                let common = space.common();
+                assert!(
+                    common.contiguous,
+                    "The large object space is not contiguous"
+                );
+                LOS_START.store(common.start.as_usize(), Ordering::Relaxed);
+                LOS_END.store((common.start + common.extent).as_usize(), Ordering::Relaxed);
+            }
+        });
+    assert!(
+        LOS_START.load(Ordering::Relaxed) < LOS_END.load(Ordering::Relaxed),
+        "Cannot find the large object space for {:?}",
+        *SINGLETON.get_options().plan
+    );
+    trace!(
+        "LOS range: {:x}-{:x}",
+        LOS_START.load(Ordering::Relaxed),
+        LOS_END.load(Ordering::Relaxed)
+    );
+}
+
 #[inline(always)]
 pub fn is_object_in_los(object: &ObjectReference) -> bool {
-    // FIXME: get the range from MMTk. Or at least assert at boot time to make sure those constants are correct.
-    (*object).to_raw_address().as_usize() >= 0x600_0000_0000
-        && (*object).to_raw_address().as_usize() < 0x800_0000_0000
+    let addr = (*object).to_raw_address().as_usize();
+    addr >= LOS_START.load(Ordering::Relaxed) && addr < LOS_END.load(Ordering::Relaxed)
+}
+
+/// The size of a large object, including its bigval header. Julia stores the size (`sz`) at the start of the bigval.
+pub unsafe fn get_lo_object_size(object: ObjectReference) -> usize {
+    debug_assert!(is_object_in_los(&object));
+    VMObjectModel::ref_to_object_start(object).load::<usize>()
 }
 
 #[inline(always)]
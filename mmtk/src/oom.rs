@@ -0,0 +1,308 @@
+// Out-of-memory handling.
+//
+// We hold back some headroom from the max heap size (see RecordedTrigger in gc_trigger.rs), so the heap runs out
+// before it reaches the max heap size. No memory is reserved for the headroom: it only lowers the limit that the GC
+// trigger sees. When MMTk reports that the heap is exhausted, we release the headroom, so the runtime can allocate
+// the exception, its backtrace, and whatever the handler needs. The headroom is held again after a GC that frees
+// enough memory.
+//
+// Before throwing the OutOfMemoryError, we print a heap summary to stderr, and optionally a heap census
+// (the number of objects and bytes for each type). The census walks the heap, which is only safe while the world is
+// stopped, so we take it at the end of a GC that leaves the heap nearly full, and print the last one.
+//
+// We do not throw the OutOfMemoryError in the binding: jl_throw_out_of_memory_error longjmps, which must not go
+// through Rust and MMTk frames. Instead, we flag it for the allocating thread, and the runtime throws it after the
+// call into the binding returns (see mmtk_take_pending_out_of_memory).
+
+use crate::config::{parse_bool, read_env_var};
+use crate::gc_trigger;
+use crate::julia_types::*;
+use crate::{JuliaVM, SINGLETON};
+use log::*;
+use mmtk::util::alloc::AllocationError;
+use mmtk::util::constants::BYTES_IN_PAGE;
+use mmtk::util::{Address, ObjectReference};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The name of the option (for `mmtk_process`) to set the size of the OOM headroom (in bytes).
+pub const OOM_HEADROOM_OPTION: &str = "oom_headroom";
+/// The environment variable to set the size of the OOM headroom (in bytes).
+pub const OOM_HEADROOM_ENV_VAR: &str = "MMTK_OOM_HEADROOM";
+/// The default size of the OOM headroom (in bytes).
+pub const DEFAULT_OOM_HEADROOM: usize = 8 * 1024 * 1024;
+/// The name of the option (for `mmtk_process`) to print a heap census when we run out of memory.
+pub const OOM_HEAP_CENSUS_OPTION: &str = "oom_heap_census";
+/// The environment variable to print a heap census when we run out of memory.
+pub const OOM_HEAP_CENSUS_ENV_VAR: &str = "MMTK_OOM_HEAP_CENSUS";
+
+/// The number of types that we print in the heap census
+const HEAP_CENSUS_TOP_TYPES: usize = 20;
+/// If the census is enabled, we take it at the end of a GC that leaves the heap at least this full
+/// (as a fraction of the max heap size).
+const HEAP_CENSUS_HEAP_RATIO: f64 = 0.9;
+
+lazy_static! {
+    static ref OOM_HEADROOM: AtomicUsize = AtomicUsize::new(
+        read_env_var(OOM_HEADROOM_ENV_VAR, parse_oom_headroom)
+            .unwrap_or(DEFAULT_OOM_HEADROOM)
+    );
+    static ref OOM_HEAP_CENSUS: AtomicBool =
+        AtomicBool::new(read_env_var(OOM_HEAP_CENSUS_ENV_VAR, parse_bool).unwrap_or(false));
+    /// The heap census taken at the end of the last GC that left the heap nearly full
+    static ref LAST_HEAP_CENSUS: Mutex<Option<HeapCensus>> = Mutex::new(None);
+}
+
+/// Set when the OOM headroom is released.
+static OOM_HEADROOM_RELEASED: AtomicBool = AtomicBool::new(false);
+
+thread_local! {
+    /// Set when the current thread needs to throw an OutOfMemoryError.
+    static PENDING_OUT_OF_MEMORY: Cell<bool> = Cell::new(false);
+}
+
+/// Set the `oom_headroom` option. Return an error message if the value is invalid. See config.rs
+pub fn set_oom_headroom(value: &str) -> Result<(), String> {
+    parse_oom_headroom(value).map(|headroom| OOM_HEADROOM.store(headroom, Ordering::Relaxed))
+}
+
+/// Set the `oom_heap_census` option. Return an error message if the value is invalid. See config.rs
+pub fn set_oom_heap_census(value: &str) -> Result<(), String> {
+    parse_bool(value)
+        .map(|census| OOM_HEAP_CENSUS.store(census, Ordering::Relaxed))
+        .map_err(|_| format!("Invalid oom_heap_census: {} (expect true or false)", value))
+}
+
+fn parse_oom_headroom(value: &str) -> Result<usize, String> {
+    value
+        .parse::<usize>()
+        .map_err(|_| format!("Invalid OOM headroom: {} (expect bytes)", value))
+}
+
+/// The size of the OOM headroom (in bytes)
+pub fn oom_headroom() -> usize {
+    OOM_HEADROOM.load(Ordering::Relaxed)
+}
+
+/// Do we print a heap census when we run out of memory?
+pub fn heap_census_enabled() -> bool {
+    OOM_HEAP_CENSUS.load(Ordering::Relaxed)
+}
+
+/// The OOM headroom that is held back from the max heap size now (in bytes)
+pub(crate) fn held_oom_headroom() -> usize {
+    if OOM_HEADROOM_RELEASED.load(Ordering::SeqCst) {
+        0
+    } else {
+        OOM_HEADROOM.load(Ordering::Relaxed)
+    }
+}
+
+/// Hold the OOM headroom again if it was released, and the heap has room for it after a GC.
+pub(crate) fn maybe_restore_oom_headroom(heap_size: usize, max_heap_size: usize) {
+    if OOM_HEADROOM_RELEASED.load(Ordering::SeqCst)
+        && heap_size + OOM_HEADROOM.load(Ordering::Relaxed) < max_heap_size
+    {
+        info!("Restored the OOM headroom");
+        OOM_HEADROOM_RELEASED.store(false, Ordering::SeqCst);
+    }
+}
+
+/// Ask the runtime to throw an OutOfMemoryError on the current thread once the binding returns.
+pub(crate) fn set_pending_out_of_memory() {
+    PENDING_OUT_OF_MEMORY.with(|pending| pending.set(true));
+}
+
+/// Does the current thread need to throw an OutOfMemoryError? This clears the request. The runtime calls this after
+/// a call into the binding that may allocate or collect (mmtk_alloc, mmtk_alloc_large or
+/// mmtk_handle_user_collection_request) returns, and throws the OutOfMemoryError if this returns true.
+#[no_mangle]
+pub extern "C" fn mmtk_take_pending_out_of_memory() -> bool {
+    PENDING_OUT_OF_MEMORY.with(|pending| pending.replace(false))
+}
+
+/// MMTk failed to allocate. Release the OOM headroom, print the diagnostics, and ask the runtime to throw an
+/// OutOfMemoryError.
+pub(crate) fn handle_out_of_memory(err_kind: AllocationError) {
+    match err_kind {
+        AllocationError::HeapOutOfMemory => {
+            eprintln!("[MMTk] Out of memory: the heap is exhausted.");
+            if !SINGLETON.get_plan().constraints().collects_garbage {
+                eprintln!(
+                    "[MMTk] The plan {:?} never collects garbage, so every allocation stays in the heap. Increase the max heap size.",
+                    *SINGLETON.get_options().plan
+                );
+            }
+        }
+        AllocationError::MmapOutOfMemory => {
+            eprintln!("[MMTk] Out of memory: failed to map memory from the OS.");
+        }
+    }
+
+    // Releasing the headroom only helps if the heap is exhausted. If mmap fails, the OS has no memory for us anyway.
+    if err_kind == AllocationError::HeapOutOfMemory
+        && !OOM_HEADROOM_RELEASED.swap(true, Ordering::SeqCst)
+    {
+        eprintln!(
+            "[MMTk] Released the OOM headroom of {} bytes",
+            OOM_HEADROOM.load(Ordering::Relaxed)
+        );
+    }
+
+    print_heap_summary();
+    if OOM_HEAP_CENSUS.load(Ordering::Relaxed) {
+        print_heap_census();
+    }
+    set_pending_out_of_memory();
+}
+
+/// Called by the GC trigger at the end of each GC, while the world is stopped. Take a heap census if it is enabled
+/// and the GC left the heap nearly full, so we have a census to print if we run out of memory.
+pub(crate) fn maybe_take_heap_census(heap_size: usize, max_heap_size: usize) {
+    if OOM_HEAP_CENSUS.load(Ordering::Relaxed)
+        && heap_size as f64 >= max_heap_size as f64 * HEAP_CENSUS_HEAP_RATIO
+    {
+        *LAST_HEAP_CENSUS.lock().unwrap() = Some(HeapCensus::take(heap_size));
+    }
+}
+
+/// NoGC never collects, so the GC trigger never asks for a GC, and MMTk does not notice that the heap is full
+/// (see RecordedTrigger in gc_trigger.rs). The GC trigger calls this instead when a space acquires pages in the
+/// allocation slow path. If the reserved pages (including the pages being acquired) exceed the hard heap limit, we
+/// report that the heap is out of memory, as MMTk would.
+pub(crate) fn check_heap_without_gc(reserved_pages: usize) {
+    let heap_size = mmtk::util::conversions::pages_to_bytes(reserved_pages);
+    if heap_size + held_oom_headroom() > gc_trigger::heap_hard_limit() {
+        use mmtk::vm::Collection;
+        crate::collection::VMCollection::out_of_memory(
+            mmtk::util::opaque_pointer::VMThread::UNINITIALIZED,
+            AllocationError::HeapOutOfMemory,
+        );
+    }
+}
+
+fn print_heap_summary() {
+    use mmtk::plan::HasSpaces;
+    use mmtk::policy::space::Space;
+
+    let plan = SINGLETON.get_plan();
+    eprintln!("[MMTk] Heap summary:");
+    eprintln!(
+        "  used: {} bytes, reserved: {} bytes, heap target: {} bytes",
+        mmtk::memory_manager::used_bytes(&SINGLETON),
+        mmtk::memory_manager::total_bytes(&SINGLETON),
+        gc_trigger::current_heap_target()
+    );
+    eprintln!(
+        "  soft limit: {} bytes, hard limit: {} bytes",
+        gc_trigger::heap_soft_limit(),
+        gc_trigger::heap_hard_limit()
+    );
+    eprintln!(
+        "  malloc and external memory: {} bytes",
+        crate::api::malloc_and_external_bytes()
+    );
+    plan.for_each_space(&mut |space: &dyn Space<JuliaVM>| {
+        eprintln!(
+            "  space {}: {} pages ({} bytes) reserved",
+            space.get_name(),
+            space.reserved_pages(),
+            space.reserved_pages() * BYTES_IN_PAGE
+        );
+    });
+    let last_gc = crate::gc_stats::last_gc_phase_times();
+    eprintln!(
+        "  last GC: {} (pause {} ns, stop the world {} ns, closure {} ns, release {} ns)",
+        if crate::collection::is_current_gc_nursery() {
+            "nursery"
+        } else {
+            "full heap"
+        },
+        last_gc.total,
+        last_gc.stop_the_world,
+        last_gc.closure,
+        last_gc.release
+    );
+}
+
+/// The number of objects and bytes for the types that use the most memory
+struct HeapCensus {
+    /// When the census was taken (jl_hrtime), and the heap size at that time (in bytes)
+    time: u64,
+    heap_size: usize,
+    /// The type name, the number of objects and their bytes, sorted by bytes
+    top_types: Vec<(String, usize, usize)>,
+}
+
+impl HeapCensus {
+    /// Count the objects and bytes for each type in the heap. This walks the heap, and reads the type of each
+    /// object, so it must only be called while the world is stopped.
+    fn take(heap_size: usize) -> Self {
+        let mut census: HashMap<usize, (usize, usize)> = HashMap::new();
+        mmtk::memory_manager::enumerate_objects(&SINGLETON, |object: ObjectReference| {
+            let ty = unsafe { crate::julia_scanning::mmtk_jl_typeof(object.to_raw_address()) };
+            let size = if crate::object_model::is_object_in_los(&object) {
+                unsafe { crate::object_model::get_lo_object_size(object) }
+            } else {
+                unsafe { crate::object_model::get_so_object_size(object) }
+            };
+            let entry = census.entry(ty as usize).or_insert((0, 0));
+            entry.0 += 1;
+            entry.1 += size;
+        });
+
+        let mut census: Vec<(usize, (usize, usize))> = census.into_iter().collect();
+        census.sort_by(|a, b| (b.1).1.cmp(&(a.1).1));
+        // Resolve the names now. The types may be collected by the time we print the census.
+        let top_types = census
+            .iter()
+            .take(HEAP_CENSUS_TOP_TYPES)
+            .map(|(ty, (count, bytes))| {
+                let name = unsafe { type_name(*ty as *const jl_datatype_t) };
+                (name, *count, *bytes)
+            })
+            .collect();
+        Self {
+            time: unsafe { crate::jl_hrtime() },
+            heap_size,
+            top_types,
+        }
+    }
+}
+
+fn print_heap_census() {
+    let census = LAST_HEAP_CENSUS.lock().unwrap();
+    let census = match census.as_ref() {
+        Some(census) => census,
+        None => {
+            eprintln!("[MMTk] No heap census: no GC has left the heap nearly full.");
+            return;
+        }
+    };
+    eprintln!(
+        "[MMTk] Heap census at the end of the last GC that left the heap nearly full ({} ns ago, heap size {} bytes):",
+        unsafe { crate::jl_hrtime() }.saturating_sub(census.time),
+        census.heap_size
+    );
+    eprintln!("  top {} types by bytes:", HEAP_CENSUS_TOP_TYPES);
+    for (name, count, bytes) in census.top_types.iter() {
+        eprintln!("  {:>12} bytes {:>10} objects  {}", bytes, count, name);
+    }
+}
+
+/// The name of a Julia type. See jl_symbol_name in julia.h
+unsafe fn type_name(ty: *const jl_datatype_t) -> String {
+    if ty.is_null() || (*ty).name.is_null() || (*(*ty).name).name.is_null() {
+        return format!("<unknown type {:?}>", ty);
+    }
+    let sym = (*(*ty).name).name;
+    let name = Address::from_ptr(sym).add(crate::object_model::llt_align(
+        std::mem::size_of::<jl_sym_t>(),
+        std::mem::size_of::<usize>(),
+    ));
+    std::ffi::CStr::from_ptr(name.to_ptr::<std::os::raw::c_char>())
+        .to_string_lossy()
+        .into_owned()
+}
@@ -0,0 +1,191 @@
+// The handshake between the mutators and the GC to stop the world.
+//
+// A mutator that triggers a GC records the time of the request in `block_for_gc`, and asks the Julia runtime to stop
+// the world (jl_gc_prepare_to_collect). The mutator that coordinates the GC registers its arrival in
+// `mmtk_block_thread_for_gc`. Julia's safepoint parks the other mutators by itself (their gc_state is not 0), so the
+// coordinator registers the arrival of a mutator when it first sees its gc_state set. It rechecks periodically, as a
+// mutator can be parked without notifying us. The runtime may also call `mmtk_safepoint_arrive` on a mutator's own
+// thread before it waits for the GC, which registers the arrival with the exact time and wakes up the coordinator.
+// Once all the registered mutators have arrived, the GC worker that waits in `stop_all_mutators` is woken up.
+//
+// We record the time to safepoint of each mutator: the time from the GC request to its arrival. For a mutator that the
+// runtime parks without calling `mmtk_safepoint_arrive`, we use the time we first saw it parked, which is an upper
+// bound.
+
+use crate::julia_types::_jl_tls_states_t;
+use crate::{jl_hrtime, MUTATORS, WORLD_HAS_STOPPED};
+use log::*;
+use mmtk::util::Address;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, AtomicI8, Ordering};
+use std::sync::{Condvar, Mutex};
+use std::time::Duration;
+
+/// How long the coordinator waits before it checks again for mutators that the runtime has parked.
+const ARRIVAL_RECHECK_INTERVAL: Duration = Duration::from_millis(1);
+
+#[derive(Default)]
+struct Handshake {
+    /// When the current GC was requested (jl_hrtime). 0 if no GC is requested.
+    requested: u64,
+    /// The mutators (by their jl_tls_states_t) that have arrived for the current GC, and when they arrived
+    arrived: HashMap<Address, u64>,
+    /// Have all the mutators arrived for the current GC?
+    world_stopped: bool,
+}
+
+lazy_static! {
+    static ref HANDSHAKE: (Mutex<Handshake>, Condvar) =
+        (Mutex::new(Handshake::default()), Condvar::new());
+    /// The time to safepoint (in nanoseconds) of each thread (by tid) in the last GC
+    static ref TIME_TO_SAFEPOINT: Mutex<HashMap<i16, u64>> = Mutex::new(HashMap::new());
+}
+
+thread_local! {
+    /// The jl_tls_states_t of the mutator that runs on this thread. Zero if this thread is not a mutator.
+    static CURRENT_MUTATOR_TLS: Cell<Address> = const { Cell::new(Address::ZERO) };
+}
+
+/// Remember the mutator of the current thread. This is called when the mutator is bound on its thread.
+pub(crate) fn set_current_mutator(tls: Address) {
+    CURRENT_MUTATOR_TLS.with(|current| current.set(tls));
+}
+
+/// A mutator requests a GC. Record the time of the first request for the GC.
+pub(crate) fn request_gc() {
+    let mut handshake = HANDSHAKE.0.lock().unwrap();
+    if handshake.requested == 0 {
+        handshake.requested = unsafe { jl_hrtime() };
+    }
+}
+
+/// Register the arrival of the mutator on the current thread, with the current time.
+fn register_arrival(handshake: &mut Handshake) {
+    let me = CURRENT_MUTATOR_TLS.with(|current| current.get());
+    if !me.is_zero() {
+        let now = unsafe { jl_hrtime() };
+        handshake.arrived.entry(me).or_insert(now);
+    }
+}
+
+/// The mutator that coordinates the GC arrives. This returns once every registered mutator has arrived.
+pub(crate) fn arrive() {
+    let (lock, cvar) = &*HANDSHAKE;
+    let mut handshake = lock.lock().unwrap();
+    register_arrival(&mut handshake);
+
+    loop {
+        if handshake.world_stopped {
+            return;
+        }
+        let now = unsafe { jl_hrtime() };
+        let mut running = vec![];
+        for tls in registered_mutator_tls() {
+            if handshake.arrived.contains_key(&tls) {
+                continue;
+            }
+            if is_parked(tls) {
+                handshake.arrived.insert(tls, now);
+            } else {
+                running.push(tls);
+            }
+        }
+        if running.is_empty() {
+            let now = unsafe { jl_hrtime() };
+            record_time_to_safepoint(&handshake);
+            if handshake.requested != 0 {
+                crate::gc_stats::record(
+                    crate::gc_stats::Event::StopTheWorld,
+                    handshake.requested,
+                    now,
+                );
+            }
+            handshake.world_stopped = true;
+            AtomicBool::store(&WORLD_HAS_STOPPED, true, Ordering::SeqCst);
+            trace!("All {} mutators have arrived", handshake.arrived.len());
+            cvar.notify_all();
+            return;
+        }
+        trace!("Mutators {:?} have not arrived", running);
+        // A running mutator either arrives with mmtk_safepoint_arrive (which notifies us), or gets parked by the runtime.
+        handshake = cvar
+            .wait_timeout(handshake, ARRIVAL_RECHECK_INTERVAL)
+            .unwrap()
+            .0;
+    }
+}
+
+/// The current mutator stops at a safepoint for a GC that another mutator coordinates. The runtime may call this on the
+/// mutator's own thread before it waits for the GC to finish (e.g. in jl_safepoint_wait_gc), so we record the exact
+/// time to safepoint. This is optional: the coordinator also sees the mutator parked. This does not block.
+#[no_mangle]
+pub extern "C" fn mmtk_safepoint_arrive() {
+    let (lock, cvar) = &*HANDSHAKE;
+    let mut handshake = lock.lock().unwrap();
+    // Ignore a mutator that arrives late, after the world has stopped, or when no GC is requested.
+    if handshake.requested == 0 || handshake.world_stopped {
+        return;
+    }
+    register_arrival(&mut handshake);
+    cvar.notify_all();
+}
+
+/// Block the GC thread until all the mutators have arrived.
+pub(crate) fn wait_for_world_stopped() {
+    let (lock, cvar) = &*HANDSHAKE;
+    let _handshake = cvar
+        .wait_while(lock.lock().unwrap(), |handshake| !handshake.world_stopped)
+        .unwrap();
+}
+
+/// The GC is done. Clear the handshake for the next GC.
+pub(crate) fn reset() {
+    *HANDSHAKE.0.lock().unwrap() = Handshake::default();
+}
+
+/// Get the time to safepoint (in nanoseconds) of the thread in the last GC. Return 0 if the thread was not seen
+/// in the last GC.
+#[no_mangle]
+pub extern "C" fn mmtk_get_time_to_safepoint(tid: i16) -> u64 {
+    TIME_TO_SAFEPOINT
+        .lock()
+        .unwrap()
+        .get(&tid)
+        .copied()
+        .unwrap_or(0)
+}
+
+fn record_time_to_safepoint(handshake: &Handshake) {
+    if handshake.requested == 0 {
+        return;
+    }
+    let mut times = TIME_TO_SAFEPOINT.lock().unwrap();
+    times.clear();
+    for (tls, arrival) in handshake.arrived.iter() {
+        let tid = unsafe { &*tls.to_ptr::<_jl_tls_states_t>() }.tid;
+        times.insert(tid, arrival.saturating_sub(handshake.requested));
+    }
+    if let Some((tid, time)) = times.iter().max_by_key(|(_, time)| **time) {
+        info!("Time to safepoint: max = {} ns (thread {})", time, tid);
+    }
+}
+
+/// The jl_tls_states_t of all the registered mutators
+fn registered_mutator_tls() -> Vec<Address> {
+    MUTATORS
+        .read()
+        .unwrap()
+        .iter()
+        .map(|entry| entry.tls)
+        .collect()
+}
+
+/// Has the runtime parked the mutator for GC? Its gc_state is not 0 if it is waiting for the GC at a safepoint
+/// (JL_GC_STATE_WAITING) or in a GC safe region (JL_GC_STATE_SAFE). Either way, it does not touch the heap until
+/// the GC is done.
+fn is_parked(tls: Address) -> bool {
+    let ptls = unsafe { &*tls.to_ptr::<_jl_tls_states_t>() };
+    let gc_state = unsafe { &*(&ptls.gc_state as *const _ as *const AtomicI8) };
+    gc_state.load(Ordering::Acquire) != 0
+}
@@ -26,6 +26,7 @@ impl Scanning<JuliaVM> for VMScanning {
         mutator: &'static mut Mutator<JuliaVM>,
         mut factory: impl RootsWorkFactory<JuliaVMSlot>,
     ) {
+        let start = unsafe { crate::jl_hrtime() };
         // This allows us to reuse mmtk_scan_gcstack which expectes an SlotVisitor
         // Push the nodes as they need to be transitively pinned
         struct SlotBuffer {
@@ -146,6 +147,7 @@ impl Scanning<JuliaVM> for VMScanning {
         for nodes in node_buffer.chunks(CAPACITY_PER_PACKET).map(|c| c.to_vec()) {
             factory.create_process_pinning_roots_work(nodes);
         }
+        crate::gc_stats::record_mutator_roots(ptls.tid, start, unsafe { crate::jl_hrtime() });
     }
 
     fn scan_vm_specific_roots(
@@ -154,9 +156,9 @@ impl Scanning<JuliaVM> for VMScanning {
     ) {
         use crate::slots::RootsWorkClosure;
         let mut roots_closure = RootsWorkClosure::from_roots_work_factory(&mut factory);
-        unsafe {
+        crate::gc_stats::time(crate::gc_stats::Event::VMSpecificRoots, || unsafe {
             jl_gc_scan_vm_specific_roots(&mut roots_closure as _);
-        }
+        });
     }
 
     fn scan_object<SV: SlotVisitor<JuliaVMSlot>>(
@@ -181,6 +183,9 @@ impl Scanning<JuliaVM> for VMScanning {
         _worker: &mut GCWorker<JuliaVM>,
         tracer_context: impl ObjectTracerContext<JuliaVM>,
     ) -> bool {
+        // MMTk's reference processing is done when we get here.
+        crate::gc_stats::mark(crate::gc_stats::Event::VMRefClosureStart);
+
         let single_thread_process_finalizer = ScanFinalizersSingleThreaded { tracer_context };
         memory_manager::add_work_packet(
             &SINGLETON,
@@ -230,8 +235,10 @@ impl Default for SweepVMSpecific {
 impl<VM: VMBinding> GCWork<VM> for SweepVMSpecific {
     fn do_work(&mut self, _worker: &mut GCWorker<VM>, _mmtk: &'static MMTK<VM>) {
         // call sweep malloced arrays and sweep stack pools
-        unsafe { jl_gc_mmtk_sweep_malloced_memory() }
-        unsafe { jl_gc_sweep_stack_pools_and_mtarraylist_buffers() }
+        crate::gc_stats::time(crate::gc_stats::Event::SweepVMSpecific, || {
+            unsafe { jl_gc_mmtk_sweep_malloced_memory() }
+            unsafe { jl_gc_sweep_stack_pools_and_mtarraylist_buffers() }
+        });
         self.swept = true;
     }
 }
@@ -242,8 +249,13 @@ pub struct ScanFinalizersSingleThreaded<C: ObjectTracerContext<JuliaVM>> {
 
 impl<C: ObjectTracerContext<JuliaVM>> GCWork<JuliaVM> for ScanFinalizersSingleThreaded<C> {
     fn do_work(&mut self, worker: &mut GCWorker<JuliaVM>, _mmtk: &'static MMTK<JuliaVM>) {
-        self.tracer_context.with_tracer(worker, |tracer| {
-            crate::julia_finalizer::scan_finalizers_in_rust(tracer);
+        crate::gc_stats::time(crate::gc_stats::Event::Finalizers, || {
+            self.tracer_context.with_tracer(worker, |tracer| {
+                if crate::julia_finalizer::scan_finalizers_in_rust(tracer) {
+                    use mmtk::vm::Collection;
+                    crate::collection::VMCollection::schedule_finalization(worker.tls);
+                }
+            });
         });
     }
 }
@@ -0,0 +1,186 @@
+// Per-space heap statistics, so tools can show where the memory goes.
+//
+// For each MMTk space (e.g. immix, los, immortal, vm_space), we report:
+//   - the pages reserved and committed by the page resource of the space,
+//   - the side metadata pages reserved for those pages,
+//   - the used pages, the live bytes and the fragmentation at the end of the last GC. MMTk only measures these if
+//     the `count_live_bytes_in_gc` option of MMTk core is set (e.g. MMTK_COUNT_LIVE_BYTES_IN_GC=true),
+//   - optionally, the number of objects and their bytes, by enumerating the objects in the heap. This walks the whole
+//     heap and reads the header of each object, so it must only be done with the world stopped: no other thread
+//     may run Julia code or allocate, and no GC may be in progress.
+// A value that is not known is MMTK_SPACE_STATS_UNKNOWN (usize::MAX).
+
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+use crate::{JuliaVM, SINGLETON};
+use libc::c_char;
+use log::*;
+use mmtk::plan::HasSpaces;
+use mmtk::policy::space::Space;
+use mmtk::util::constants::BYTES_IN_PAGE;
+use mmtk::util::heap::PageResource;
+use mmtk::util::{Address, ObjectReference};
+
+/// The value for a statistic that is not known
+pub const SPACE_STATS_UNKNOWN: usize = usize::MAX;
+
+/// The max length of a space name, including the NUL terminator
+const SPACE_NAME_LEN: usize = 32;
+
+/// The statistics of a space. This maps to MMTkSpaceStats in mmtk.h.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct HeapSpaceStats {
+    /// The name of the space (NUL terminated)
+    pub name: [c_char; SPACE_NAME_LEN],
+    /// The address range of the space. The extent is 0 if the space is not contiguous.
+    pub start: Address,
+    pub extent: usize,
+    /// The data pages reserved by the space
+    pub reserved_pages: usize,
+    /// The data pages committed (mapped) by the space
+    pub committed_pages: usize,
+    /// The side metadata pages reserved for the data pages of the space
+    pub metadata_pages: usize,
+    /// The pages used by objects at the end of the last GC
+    pub used_pages: usize,
+    /// The live bytes at the end of the last GC
+    pub live_bytes: usize,
+    /// The fraction of the used bytes that are not live at the end of the last GC, in per mille
+    pub fragmentation_per_mille: usize,
+    /// The number of objects in the space, if the objects are counted
+    pub objects: usize,
+    /// The bytes of the objects in the space, if the objects are counted. Not known for large objects.
+    pub object_bytes: usize,
+}
+
+impl HeapSpaceStats {
+    fn new(space: &dyn Space<JuliaVM>) -> Self {
+        let mut name = [0 as c_char; SPACE_NAME_LEN];
+        for (i, byte) in space
+            .get_name()
+            .bytes()
+            .take(SPACE_NAME_LEN - 1)
+            .enumerate()
+        {
+            name[i] = byte as c_char;
+        }
+        let common = space.common();
+        let page_resource = space.get_page_resource();
+        let reserved_pages = page_resource.reserved_pages();
+        Self {
+            name,
+            start: common.start,
+            extent: if common.contiguous { common.extent } else { 0 },
+            reserved_pages,
+            committed_pages: page_resource.committed_pages(),
+            metadata_pages: space.reserved_pages().saturating_sub(reserved_pages),
+            used_pages: SPACE_STATS_UNKNOWN,
+            live_bytes: SPACE_STATS_UNKNOWN,
+            fragmentation_per_mille: SPACE_STATS_UNKNOWN,
+            objects: SPACE_STATS_UNKNOWN,
+            object_bytes: SPACE_STATS_UNKNOWN,
+        }
+    }
+
+    fn contains(&self, object: ObjectReference) -> bool {
+        let addr = object.to_raw_address();
+        self.extent != 0 && addr >= self.start && addr < self.start + self.extent
+    }
+}
+
+/// Collect the statistics of all the spaces. If `count_objects` is true, enumerate the objects in the heap and count
+/// them for each space.
+pub fn space_stats(count_objects: bool) -> Vec<HeapSpaceStats> {
+    let mut stats = vec![];
+    let live_bytes = mmtk::memory_manager::live_bytes_in_last_gc(&SINGLETON);
+    SINGLETON
+        .get_plan()
+        .for_each_space(&mut |space: &dyn Space<JuliaVM>| {
+            let mut entry = HeapSpaceStats::new(space);
+            if let Some(live) = live_bytes.get(space.get_name()) {
+                entry.used_pages = live.used_pages;
+                entry.live_bytes = live.live_bytes;
+                if live.used_bytes != 0 {
+                    entry.fragmentation_per_mille =
+                        1000 - live.live_bytes.min(live.used_bytes) * 1000 / live.used_bytes;
+                }
+            }
+            stats.push(entry);
+        });
+
+    if count_objects {
+        count_objects_in_spaces(&mut stats);
+    }
+    stats
+}
+
+/// Count the objects and their bytes in each space. The caller must make sure that the world is stopped.
+/// We cannot check that other threads do not run, but we do not walk the heap while a GC is in progress.
+fn count_objects_in_spaces(stats: &mut [HeapSpaceStats]) {
+    if crate::collection::gc_in_progress() {
+        warn!("Cannot count the objects while a GC is in progress");
+        return;
+    }
+    for entry in stats.iter_mut().filter(|entry| entry.extent != 0) {
+        entry.objects = 0;
+        entry.object_bytes = 0;
+    }
+    mmtk::memory_manager::enumerate_objects(&SINGLETON, |object: ObjectReference| {
+        if let Some(entry) = stats.iter_mut().find(|entry| entry.contains(object)) {
+            entry.objects += 1;
+            entry.object_bytes += if crate::object_model::is_object_in_los(&object) {
+                unsafe { crate::object_model::get_lo_object_size(object) }
+            } else {
+                unsafe { crate::object_model::get_so_object_size(object) }
+            };
+        }
+    });
+}
+
+/// Get the statistics of the spaces. Write up to `len` entries to `stats`, and return the number of spaces.
+/// Call with `len` = 0 to get the number of spaces. If `count_objects` is true, the objects in each space are
+/// counted, which walks the whole heap. Only set `count_objects` with the world stopped.
+#[no_mangle]
+pub extern "C" fn mmtk_get_space_stats(
+    stats: *mut HeapSpaceStats,
+    len: usize,
+    count_objects: bool,
+) -> usize {
+    let all = space_stats(count_objects && len != 0);
+    for (i, entry) in all.iter().take(len).enumerate() {
+        unsafe { stats.add(i).write(*entry) };
+    }
+    all.len()
+}
+
+/// Print the statistics of the spaces to stderr. Like `mmtk_get_space_stats`, only set `count_objects` with the
+/// world stopped.
+#[no_mangle]
+pub extern "C" fn mmtk_print_space_stats(count_objects: bool) {
+    let show = |value: usize| {
+        if value == SPACE_STATS_UNKNOWN {
+            "-".to_string()
+        } else {
+            value.to_string()
+        }
+    };
+    eprintln!(
+        "[MMTk] Space statistics (pages of {} bytes):",
+        BYTES_IN_PAGE
+    );
+    for entry in space_stats(count_objects) {
+        let name = unsafe { std::ffi::CStr::from_ptr(entry.name.as_ptr()) };
+        eprintln!(
+            "  {:<12} reserved {} committed {} metadata {} used {} live bytes {} fragmentation {}/1000 objects {} object bytes {}",
+            name.to_string_lossy(),
+            entry.reserved_pages,
+            entry.committed_pages,
+            entry.metadata_pages,
+            show(entry.used_pages),
+            show(entry.live_bytes),
+            show(entry.fragmentation_per_mille),
+            show(entry.objects),
+            show(entry.object_bytes)
+        );
+    }
+}
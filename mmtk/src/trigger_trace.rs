@@ -0,0 +1,518 @@
+// Record the inputs of the GC trigger policies for each GC, and replay them through a policy.
+//
+// Set the `gc_trigger_trace` option (or MMTK_GC_TRIGGER_TRACE) to a path to record a trace. The first line is the format version (`version,<n>`), and
+// each following line is one event:
+//   pending,<pages>
+//   start,<time>,<reserved_pages>,<user_triggered>,<full_heap>,<remset_objects>,<malloc_bytes>,<memory_limit>,<soft_limit>,<hard_limit>
+//   end,<time>,<reserved_pages>,<user_triggered>,<full_heap>,<remset_objects>,<malloc_bytes>,<memory_limit>,<soft_limit>,<hard_limit>
+// where <full_heap> is `-` for non-generational plans, <memory_limit> is `-` if there is no cgroup limit, and
+// <soft_limit>/<hard_limit> are `-` if the limit is not set at runtime.
+// A trace is only parsed if its version matches TRACE_FORMAT_VERSION.
+//
+// A trace can be replayed with `replay()` through any policy that implements ReplayableTrigger, e.g.
+//   let events = parse_trace(&std::fs::read_to_string(path)?)?;
+//   let steps = replay(&events, &JuliaGCTrigger::new(max_heap_size));
+// The timestamps in the trace are used as the clock, so replaying a trace is deterministic.
+
+use crate::config::read_env_var;
+use crate::gc_trigger::ReplayableTrigger;
+use crate::JuliaVM;
+use log::*;
+use mmtk::MMTK;
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+/// The name of the option (for `mmtk_process`) for the path of the trace file.
+pub const GC_TRIGGER_TRACE_OPTION: &str = "gc_trigger_trace";
+/// The environment variable for the path of the trace file.
+pub const GC_TRIGGER_TRACE_ENV_VAR: &str = "MMTK_GC_TRIGGER_TRACE";
+
+/// The version of the trace format. Bump this whenever the fields of the events change. Version 1 was the first
+/// format, version 2 added the runtime heap limits, and version 3 added the malloc bytes.
+pub const TRACE_FORMAT_VERSION: u32 = 3;
+
+lazy_static! {
+    static ref TRACE_WRITER: Mutex<Option<BufWriter<File>>> = Mutex::new(
+        read_env_var(GC_TRIGGER_TRACE_ENV_VAR, open_trace_file)
+    );
+    /// The path of the trace file that we record to
+    static ref TRACE_FILE: Mutex<Option<String>> = Mutex::new(None);
+}
+
+/// Set the `gc_trigger_trace` option. Return an error message if the trace file cannot be created. See config.rs
+pub fn set_trace_file(value: &str) -> Result<(), String> {
+    let writer = open_trace_file(value);
+    let result = writer.as_ref().map(|_| ()).map_err(|e| e.clone());
+    *TRACE_WRITER.lock().unwrap() = writer.ok();
+    result
+}
+
+fn open_trace_file(path: &str) -> Result<BufWriter<File>, String> {
+    let mut writer = match File::create(path) {
+        Ok(file) => BufWriter::new(file),
+        Err(e) => return Err(format!("Failed to create GC trigger trace {}: {}", path, e)),
+    };
+    match writeln!(writer, "version,{}", TRACE_FORMAT_VERSION) {
+        Ok(()) => {
+            info!("Recording GC trigger trace to {}", path);
+            *TRACE_FILE.lock().unwrap() = Some(path.to_string());
+            Ok(writer)
+        }
+        Err(e) => Err(format!("Failed to write GC trigger trace {}: {}", path, e)),
+    }
+}
+
+/// The path of the trace file. None if we are not recording a trace.
+pub fn trace_file() -> Option<String> {
+    if TRACE_WRITER.lock().unwrap().is_none() {
+        return None;
+    }
+    TRACE_FILE.lock().unwrap().clone()
+}
+
+/// The inputs that a GC trigger policy sees at the start or the end of a GC.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TriggerInputs {
+    /// The current time in nanoseconds (jl_hrtime)
+    pub time: u64,
+    /// The reserved pages of the plan
+    pub reserved_pages: usize,
+    /// Is the GC triggered by the user?
+    pub user_triggered: bool,
+    /// None if the plan is not generational. At the start of a GC, this is whether the previous GC was a full heap GC.
+    /// At the end of a GC, this is whether this GC was a full heap GC.
+    pub full_heap: Option<bool>,
+    /// The number of objects added to the remembered set by the barrier since the last GC
+    pub remset_objects: usize,
+    /// The bytes allocated with malloc and the external memory reported to the binding. They count toward the heap size.
+    pub malloc_bytes: usize,
+    /// The cgroup memory limit in bytes. usize::MAX if there is no limit.
+    pub memory_limit: usize,
+    /// The soft heap limit set at runtime in bytes. usize::MAX if it is not set.
+    pub soft_limit: usize,
+    /// The hard heap limit set at runtime in bytes. usize::MAX if it is not set.
+    pub hard_limit: usize,
+}
+
+impl TriggerInputs {
+    pub fn at_gc_start(mmtk: &'static MMTK<JuliaVM>) -> Self {
+        Self {
+            full_heap: mmtk
+                .get_plan()
+                .generational()
+                .map(|gen| gen.last_collection_full_heap()),
+            remset_objects: crate::gc_trigger::REMSET_OBJECTS.swap(0, Ordering::Relaxed),
+            ..Self::now(mmtk)
+        }
+    }
+
+    pub fn at_gc_end(mmtk: &'static MMTK<JuliaVM>) -> Self {
+        Self {
+            full_heap: mmtk
+                .get_plan()
+                .generational()
+                .map(|gen| !gen.is_current_gc_nursery()),
+            ..Self::now(mmtk)
+        }
+    }
+
+    fn now(mmtk: &'static MMTK<JuliaVM>) -> Self {
+        Self {
+            time: unsafe { crate::jl_hrtime() },
+            reserved_pages: mmtk.get_plan().get_reserved_pages(),
+            user_triggered: mmtk.is_user_triggered_collection(),
+            full_heap: None,
+            remset_objects: 0,
+            malloc_bytes: crate::api::malloc_and_external_bytes(),
+            memory_limit: crate::cgroup::memory_limit().unwrap_or(usize::MAX),
+            soft_limit: crate::gc_trigger::runtime_heap_soft_limit().unwrap_or(usize::MAX),
+            hard_limit: crate::gc_trigger::runtime_heap_hard_limit().unwrap_or(usize::MAX),
+        }
+    }
+
+    /// The max heap size for a policy that was created with the given max heap size. The hard limit set at runtime
+    /// replaces the configured size, and the cgroup limit bounds both.
+    pub fn max_heap_size(&self, configured: usize) -> usize {
+        let max_heap_size = match self.hard_limit {
+            usize::MAX => configured,
+            hard_limit => hard_limit,
+        };
+        std::cmp::min(max_heap_size, self.memory_limit)
+    }
+
+    /// Pick up changes of the heap limits and the cgroup memory limit at the end of a GC: store the max heap size for a
+    /// policy that was created with the given max heap size, and return it.
+    pub fn update_max_heap_size(&self, configured: usize, max_heap_size: &AtomicUsize) -> usize {
+        let max = self.max_heap_size(configured);
+        max_heap_size.store(max, Ordering::Relaxed);
+        max
+    }
+
+    /// The soft limit set at runtime, bounded by the given max heap size. None if it is not set.
+    pub fn soft_limit(&self, max_heap_size: usize) -> Option<usize> {
+        match self.soft_limit {
+            usize::MAX => None,
+            soft_limit => Some(std::cmp::min(soft_limit, max_heap_size)),
+        }
+    }
+}
+
+/// An event in the trace
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TriggerEvent {
+    PendingAllocation { pages: usize },
+    GCStart(TriggerInputs),
+    GCEnd(TriggerInputs),
+}
+
+/// Record an event if tracing is enabled.
+pub fn record(event: &TriggerEvent) {
+    let mut writer = TRACE_WRITER.lock().unwrap();
+    if let Some(w) = writer.as_mut() {
+        let res = writeln!(w, "{}", event).and_then(|_| match event {
+            // Make sure we have complete GCs in the trace if the process is killed.
+            TriggerEvent::GCEnd(_) => w.flush(),
+            _ => Ok(()),
+        });
+        if let Err(e) = res {
+            warn!("Failed to write GC trigger trace: {}. Stop tracing.", e);
+            *writer = None;
+        }
+    }
+}
+
+impl fmt::Display for TriggerInputs {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{},{},{},{},{},{},",
+            self.time,
+            self.reserved_pages,
+            self.user_triggered as u8,
+            match self.full_heap {
+                Some(full_heap) => (full_heap as u8).to_string(),
+                None => "-".to_string(),
+            },
+            self.remset_objects,
+            self.malloc_bytes
+        )?;
+        let limit = |limit: usize| match limit {
+            usize::MAX => "-".to_string(),
+            limit => limit.to_string(),
+        };
+        write!(
+            f,
+            "{},{},{}",
+            limit(self.memory_limit),
+            limit(self.soft_limit),
+            limit(self.hard_limit)
+        )
+    }
+}
+
+impl fmt::Display for TriggerEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TriggerEvent::PendingAllocation { pages } => write!(f, "pending,{}", pages),
+            TriggerEvent::GCStart(inputs) => write!(f, "start,{}", inputs),
+            TriggerEvent::GCEnd(inputs) => write!(f, "end,{}", inputs),
+        }
+    }
+}
+
+fn parse_field<T: FromStr>(field: Option<&str>, name: &str) -> Result<T, String> {
+    let field = field.ok_or_else(|| format!("missing {}", name))?;
+    field
+        .parse::<T>()
+        .map_err(|_| format!("invalid {}: {}", name, field))
+}
+
+impl FromStr for TriggerInputs {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut fields = s.split(',');
+        let time = parse_field(fields.next(), "time")?;
+        let reserved_pages = parse_field(fields.next(), "reserved_pages")?;
+        let user_triggered = parse_field::<u8>(fields.next(), "user_triggered")? != 0;
+        let full_heap = match fields.next() {
+            Some("-") => None,
+            field => Some(parse_field::<u8>(field, "full_heap")? != 0),
+        };
+        let remset_objects = parse_field(fields.next(), "remset_objects")?;
+        let malloc_bytes = parse_field(fields.next(), "malloc_bytes")?;
+        let mut limit = |name: &str| match fields.next() {
+            Some("-") => Ok(usize::MAX),
+            field => parse_field(field, name),
+        };
+        let memory_limit = limit("memory_limit")?;
+        let soft_limit = limit("soft_limit")?;
+        let hard_limit = limit("hard_limit")?;
+        Ok(Self {
+            time,
+            reserved_pages,
+            user_triggered,
+            full_heap,
+            remset_objects,
+            malloc_bytes,
+            memory_limit,
+            soft_limit,
+            hard_limit,
+        })
+    }
+}
+
+impl FromStr for TriggerEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(',') {
+            Some(("pending", pages)) => Ok(TriggerEvent::PendingAllocation {
+                pages: parse_field(Some(pages), "pages")?,
+            }),
+            Some(("start", inputs)) => Ok(TriggerEvent::GCStart(inputs.parse()?)),
+            Some(("end", inputs)) => Ok(TriggerEvent::GCEnd(inputs.parse()?)),
+            _ => Err(format!("unknown event: {}", s)),
+        }
+    }
+}
+
+/// Parse a trace. Empty lines and lines starting with '#' are ignored. The first line must be the version of
+/// the format, and the version must be TRACE_FORMAT_VERSION.
+pub fn parse_trace(trace: &str) -> Result<Vec<TriggerEvent>, String> {
+    let mut lines = trace
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty() && !line.starts_with('#'));
+    match lines.next() {
+        Some((i, line)) => match line.trim().split_once(',') {
+            Some(("version", version)) => {
+                let version = parse_field::<u32>(Some(version), "version")
+                    .map_err(|e| format!("line {}: {}", i + 1, e))?;
+                if version != TRACE_FORMAT_VERSION {
+                    return Err(format!(
+                        "line {}: unsupported trace format version {} (expect {})",
+                        i + 1,
+                        version,
+                        TRACE_FORMAT_VERSION
+                    ));
+                }
+            }
+            _ => return Err(format!("line {}: missing trace format version", i + 1)),
+        },
+        None => return Ok(vec![]),
+    }
+    lines
+        .map(|(i, line)| {
+            line.trim()
+                .parse::<TriggerEvent>()
+                .map_err(|e| format!("line {}: {}", i + 1, e))
+        })
+        .collect()
+}
+
+/// The decision of a policy after a GC in a replay
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReplayStep {
+    /// The time when the GC ended
+    pub time: u64,
+    /// The heap size after the GC (in pages)
+    pub reserved_pages: usize,
+    /// The heap target after the GC (in bytes)
+    pub heap_target: usize,
+    /// The max heap size after the GC (in bytes)
+    pub max_heap_size: usize,
+    /// Does the policy ask for a full heap GC next?
+    pub force_full_heap: bool,
+}
+
+/// Replay the events through a policy, and return the decisions of the policy after each GC.
+pub fn replay<T: ReplayableTrigger + ?Sized>(
+    events: &[TriggerEvent],
+    policy: &T,
+) -> Vec<ReplayStep> {
+    let mut steps = vec![];
+    for event in events {
+        match event {
+            TriggerEvent::PendingAllocation { pages } => policy.pending_allocation(*pages),
+            TriggerEvent::GCStart(inputs) => policy.gc_start(inputs),
+            TriggerEvent::GCEnd(inputs) => {
+                let force_full_heap = policy.gc_end(inputs);
+                steps.push(ReplayStep {
+                    time: inputs.time,
+                    reserved_pages: inputs.reserved_pages,
+                    heap_target: policy.heap_target(),
+                    max_heap_size: policy.max_heap_size(),
+                    force_full_heap,
+                });
+            }
+        }
+    }
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gc_trigger::{
+        FixedHeapSizeTrigger, JuliaGCTrigger, MemBalancerTrigger, PauseTargetTrigger,
+    };
+
+    const MB: usize = 1024 * 1024;
+    /// A trace of a generational plan with nursery GCs, a user triggered GC, and heap limits set at runtime
+    const FIXTURE: &str = include_str!("../tests/fixtures/gc_trigger.trace");
+
+    fn step(
+        time: u64,
+        reserved_pages: usize,
+        heap_target: usize,
+        max_heap_size: usize,
+        force_full_heap: bool,
+    ) -> ReplayStep {
+        ReplayStep {
+            time,
+            reserved_pages,
+            heap_target,
+            max_heap_size,
+            force_full_heap,
+        }
+    }
+
+    fn replay_fixture<T: ReplayableTrigger>(policy: &T) -> Vec<ReplayStep> {
+        replay(&parse_trace(FIXTURE).unwrap(), policy)
+    }
+
+    #[test]
+    fn parse_events() {
+        let trace = "version,3\n# comment\n\npending,12\nstart,100,256,1,-,7,4096,-,-,-\nend,200,128,0,1,0,0,1024,512,-\n";
+        assert_eq!(
+            parse_trace(trace).unwrap(),
+            vec![
+                TriggerEvent::PendingAllocation { pages: 12 },
+                TriggerEvent::GCStart(TriggerInputs {
+                    time: 100,
+                    reserved_pages: 256,
+                    user_triggered: true,
+                    full_heap: None,
+                    remset_objects: 7,
+                    malloc_bytes: 4096,
+                    memory_limit: usize::MAX,
+                    soft_limit: usize::MAX,
+                    hard_limit: usize::MAX,
+                }),
+                TriggerEvent::GCEnd(TriggerInputs {
+                    time: 200,
+                    reserved_pages: 128,
+                    user_triggered: false,
+                    full_heap: Some(true),
+                    remset_objects: 0,
+                    malloc_bytes: 0,
+                    memory_limit: 1024,
+                    soft_limit: 512,
+                    hard_limit: usize::MAX,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn events_round_trip() {
+        let events = parse_trace(FIXTURE).unwrap();
+        let trace: String = std::iter::once(format!("version,{}", TRACE_FORMAT_VERSION))
+            .chain(events.iter().map(|event| event.to_string()))
+            .collect::<Vec<_>>()
+            .join("\n");
+        assert_eq!(parse_trace(&trace).unwrap(), events);
+    }
+
+    #[test]
+    fn reject_other_versions() {
+        assert_eq!(parse_trace(""), Ok(vec![]));
+        assert!(parse_trace("pending,1\n").is_err());
+        assert!(parse_trace("version,2\npending,1\n").is_err());
+        assert!(parse_trace("version,x\npending,1\n").is_err());
+    }
+
+    #[test]
+    fn reject_malformed_events() {
+        let err = |event: &str| parse_trace(&format!("version,3\n{}\n", event)).unwrap_err();
+        assert_eq!(err("resume,1"), "line 2: unknown event: resume,1");
+        assert_eq!(err("pending,x"), "line 2: invalid pages: x");
+        assert_eq!(err("start,1,2,0,-,0,0,-,-"), "line 2: missing hard_limit");
+        assert_eq!(
+            err("end,1,-1,0,-,0,0,-,-,-"),
+            "line 2: invalid reserved_pages: -1"
+        );
+    }
+
+    #[test]
+    fn replay_stock() {
+        assert_eq!(
+            replay_fixture(&JuliaGCTrigger::new(1024 * MB)),
+            vec![
+                step(1050000000, 4096, 45875200, 1073741824, false),
+                step(2060000000, 6144, 45875200, 1073741824, false),
+                step(3070000000, 10240, 65201870, 1073741824, false),
+                step(4080000000, 12288, 73011948, 1073741824, false),
+                // A user triggered GC after a nursery GC forces the next GC to be full heap.
+                step(5100000000, 8192, 73011948, 1073741824, true),
+                step(6050000000, 16384, 73891840, 1073741824, true),
+                // The target is bounded by the hard limit.
+                step(7080000000, 14336, 50331648, 50331648, true),
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_fixed() {
+        assert_eq!(
+            replay_fixture(&FixedHeapSizeTrigger::new(256 * MB)),
+            vec![
+                step(1050000000, 4096, 268435456, 268435456, false),
+                step(2060000000, 6144, 268435456, 268435456, false),
+                step(3070000000, 10240, 268435456, 268435456, false),
+                step(4080000000, 12288, 268435456, 268435456, false),
+                step(5100000000, 8192, 268435456, 268435456, false),
+                step(6050000000, 16384, 67108864, 268435456, false),
+                step(7080000000, 14336, 50331648, 50331648, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_membalancer() {
+        assert_eq!(
+            replay_fixture(&MemBalancerTrigger::new(16 * MB, 1024 * MB)),
+            vec![
+                step(1050000000, 4096, 33554432, 1073741824, false),
+                step(2060000000, 6144, 36022929, 1073741824, false),
+                step(3070000000, 10240, 53076597, 1073741824, false),
+                step(4080000000, 12288, 60388063, 1073741824, false),
+                step(5100000000, 8192, 46471109, 1073741824, false),
+                step(6050000000, 16384, 72843264, 1073741824, false),
+                step(7080000000, 14336, 50331648, 50331648, false),
+            ]
+        );
+    }
+
+    #[test]
+    fn replay_pause_target() {
+        assert_eq!(
+            replay_fixture(&PauseTargetTrigger::new(16 * MB, 1024 * MB)),
+            vec![
+                step(1050000000, 4096, 33554432, 1073741824, false),
+                step(2060000000, 6144, 31948800, 1073741824, false),
+                step(3070000000, 10240, 49774592, 1073741824, false),
+                step(4080000000, 12288, 58163200, 1073741824, false),
+                step(5100000000, 8192, 39288832, 1073741824, false),
+                step(6050000000, 16384, 72843264, 1073741824, false),
+                // The target is bounded by the hard limit, even though the live heap exceeds it.
+                step(7080000000, 14336, 50331648, 50331648, false),
+            ]
+        );
+    }
+}